@@ -0,0 +1,80 @@
+//! A bulk transfer over one connection while many other connections have a thread blocked in
+//! `read`. Only the threads of the connection that got something should wake up, so the CPU
+//! time the transfer takes should hardly grow with the number of idle readers.
+//!
+//! Run with `cargo bench --bench idle_readers`.
+
+use std::io::prelude::*;
+use std::net::SocketAddrV4;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+use common::{interfaces, SERVER};
+
+const BYTES: usize = 1 << 20;
+
+/// CPU time this process spent so far, in user and kernel mode
+fn cpu_time() -> Duration {
+    let stat = std::fs::read_to_string("/proc/self/stat").unwrap();
+    // the command may contain spaces, but not the fields after it
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    // USER_HZ, which is 100 pretty much everywhere
+    Duration::from_millis(ticks * 10)
+}
+
+/// Wall and CPU time of the transfer with `idle` other connections waiting for data that never
+/// comes
+fn transfer(idle: usize) -> (Duration, Duration) {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+
+    let mut streams = Vec::new();
+    let mut readers = Vec::new();
+    for _ in 0..idle {
+        streams.push(client.connect(SocketAddrV4::new(SERVER, 80)).unwrap());
+        let mut accepted = listener.accept().unwrap();
+        // woken up with an error once the interfaces go away
+        readers.push(thread::spawn(move || accepted.read(&mut [0u8; 16])));
+    }
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    let mut sink = listener.accept().unwrap();
+    // let the handshakes settle
+    thread::sleep(Duration::from_millis(100));
+
+    let (start, cpu) = (Instant::now(), cpu_time());
+    let receiver = thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        let mut received = 0;
+        while received < BYTES {
+            received += sink.read(&mut buf).unwrap();
+        }
+        sink
+    });
+    let data = vec![7u8; 64 << 10];
+    for _ in 0..BYTES / data.len() {
+        stream.write_all(&data).unwrap();
+    }
+    drop(receiver.join().unwrap());
+    let elapsed = (start.elapsed(), cpu_time() - cpu);
+
+    drop(stream);
+    drop(streams);
+    drop(listener);
+    drop(client);
+    drop(server);
+    for reader in readers {
+        let _ = reader.join().unwrap();
+    }
+    elapsed
+}
+
+fn main() {
+    for &idle in &[0, 50, 200] {
+        let (wall, cpu) = transfer(idle);
+        println!("{:>4} idle readers: {:>8.1?} wall, {:>8.1?} CPU for {} KiB", idle, wall, cpu, BYTES >> 10);
+    }
+}
@@ -0,0 +1,137 @@
+//! Async streams and listeners for tokio.
+//!
+//! They never block a thread: where their blocking counterparts wait on the condition variables
+//! of the interface, they leave the waker of their task with the interface, which wakes it once
+//! the connection or listener has something for it.
+
+use std::future;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::Interface;
+
+/// A connection of an `Interface` that implements tokio's `AsyncRead` and `AsyncWrite`.
+pub struct TcpStream {
+    inner: crate::TcpStream,
+}
+
+impl TcpStream {
+    /// Open a connection to `addr` on `iface`, and wait for the handshake to complete.
+    pub async fn connect(iface: &mut Interface, addr: impl Into<SocketAddr>) -> io::Result<Self> {
+        let stream = TcpStream::from_std(iface.open(addr.into())?);
+        future::poll_fn(|cx| stream.poll_connected(cx)).await?;
+        Ok(stream)
+    }
+
+    /// Make a blocking stream async.
+    pub fn from_std(stream: crate::TcpStream) -> Self {
+        TcpStream { inner: stream }
+    }
+
+    fn poll_connected(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let s = &self.inner;
+        let mut shard = s.h.shard(&s.quad);
+        match s.connected(&mut shard) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                // the handshake completing makes the connection writable
+                shard.wait_list(s.quad).write = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let s = &self.inner;
+        let mut shard = s.h.shard(&s.quad);
+        let unfilled = buf.initialize_unfilled();
+        match s.receive_now(&mut shard, &mut |incoming| crate::take_front(incoming, unfilled)) {
+            Some(Ok(n)) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Some(Err(e)) => Poll::Ready(Err(e)),
+            None => {
+                shard.wait_list(s.quad).read = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let s = &self.inner;
+        let mut shard = s.h.shard(&s.quad);
+        match s.write_now(&mut shard, buf) {
+            Some(r) => Poll::Ready(r),
+            None => {
+                shard.wait_list(s.quad).write = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Wait until the peer acknowledged everything written so far.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let s = &self.inner;
+        let mut shard = s.h.shard(&s.quad);
+        match s.flushed(&mut shard) {
+            Ok(false) => {
+                shard.wait_list(s.quad).write = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            r => Poll::Ready(r.map(|_| ())),
+        }
+    }
+
+    /// Send a FIN once everything written so far went out.
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.shutdown(Shutdown::Write))
+    }
+}
+
+/// A listener of an `Interface` whose `accept` is a future.
+pub struct TcpListener {
+    inner: crate::TcpListener,
+}
+
+impl TcpListener {
+    /// Listen on `port` of `iface`.
+    pub fn bind(iface: &mut Interface, port: u16) -> io::Result<Self> {
+        Ok(TcpListener::from_std(iface.bind(port)?))
+    }
+
+    /// Make a blocking listener async.
+    pub fn from_std(listener: crate::TcpListener) -> Self {
+        TcpListener { inner: listener }
+    }
+
+    /// Wait for the next connection.
+    pub async fn accept(&self) -> io::Result<TcpStream> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<TcpStream>> {
+        let l = &self.inner;
+        let mut cm = l.h.manager.lock().unwrap();
+        match l.accept_now(&mut cm) {
+            Some(r) => Poll::Ready(r.map(TcpStream::from_std)),
+            None => {
+                cm.listener(l.port).read = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Stop listening on this port. Tasks waiting in `accept` get an error.
+    pub fn close(&self) -> io::Result<()> {
+        self.inner.close()
+    }
+}
@@ -0,0 +1,98 @@
+//! The Internet checksum (RFC 1071).
+
+use std::net::IpAddr;
+
+/// A running one's complement sum that takes its data in chunks of any length.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Checksum {
+    sum: u64,
+    /// whether the chunks so far had an odd number of bytes in total
+    odd: bool,
+}
+
+impl Checksum {
+    /// Start with the pseudo header that TCP and UDP checksums cover (RFC 793 S3.1), and that
+    /// of IPv6, which ICMPv6 checksums cover as well (RFC 8200 S8.1).
+    pub(crate) fn pseudo_header(src: IpAddr, dst: IpAddr, protocol: u8, len: usize) -> Self {
+        let mut c = Checksum::default();
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                c.add(&src.octets());
+                c.add(&dst.octets());
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                c.add(&src.octets());
+                c.add(&dst.octets());
+            }
+            _ => unreachable!("addresses of different families"),
+        }
+        // the 32 bit length of IPv6 sums up the same as the 16 bit one of IPv4
+        c.sum += protocol as u64 + len as u64;
+        c
+    }
+
+    pub(crate) fn add(&mut self, data: &[u8]) {
+        // 32 bit words add up to the same as 16 bit ones once folded, in half the steps
+        let mut sum = 0u64;
+        let mut words = data.chunks_exact(4);
+        for w in &mut words {
+            sum += u32::from_be_bytes([w[0], w[1], w[2], w[3]]) as u64;
+        }
+        let rest = words.remainder();
+        let mut pairs = rest.chunks_exact(2);
+        for p in &mut pairs {
+            sum += u16::from_be_bytes([p[0], p[1]]) as u64;
+        }
+        if let [b] = pairs.remainder() {
+            sum += (*b as u64) << 8;
+        }
+
+        let mut sum = fold(sum);
+        if self.odd {
+            // our bytes sit one position later in the words than we counted them, and swapping
+            // the bytes of a one's complement sum is the same as swapping them in the data
+            sum = sum.swap_bytes();
+        }
+        self.sum += sum as u64;
+        self.odd ^= data.len() % 2 == 1;
+    }
+
+    /// The folded sum, which links that fill in checksums expect to find in the checksum field
+    pub(crate) fn partial(&self) -> u16 {
+        fold(self.sum)
+    }
+
+    /// The checksum that goes into the header
+    pub(crate) fn finish(&self) -> u16 {
+        !self.partial()
+    }
+}
+
+fn fold(mut sum: u64) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Whether an IPv4 header, checksum field included, is intact
+pub(crate) fn ipv4_header_ok(header: &[u8]) -> bool {
+    let mut c = Checksum::default();
+    c.add(header);
+    c.finish() == 0
+}
+
+/// Whether a TCP, UDP or ICMPv6 message from `src` to `dst`, checksum field included, is intact
+pub(crate) fn transport_ok(src: IpAddr, dst: IpAddr, protocol: u8, segment: &[u8]) -> bool {
+    let mut c = Checksum::pseudo_header(src, dst, protocol, segment.len());
+    c.add(segment);
+    c.finish() == 0
+}
+
+/// Whether an ICMP message, checksum field included, is intact. Unlike TCP, UDP and ICMPv6,
+/// ICMP sums up the message alone.
+pub(crate) fn icmp_ok(message: &[u8]) -> bool {
+    let mut c = Checksum::default();
+    c.add(message);
+    c.finish() == 0
+}
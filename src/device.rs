@@ -0,0 +1,413 @@
+//! The link layer the stack sends and receives IP packets on.
+
+use std::io;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+use crate::event_fd::EventFd;
+
+/// A link that carries raw IP packets to and from the stack.
+///
+/// The packet thread owns the device, so implementations don't need to be shareable.
+pub trait Device: Send + 'static {
+    /// Receive one packet into `buf`, waiting no later than `deadline` for it to arrive.
+    ///
+    /// Returns `Ok(None)` if nothing arrived in time. The stack uses the timeout to run its
+    /// timers, so spurious early returns are fine.
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>>;
+
+    /// Send one packet. The whole of `buf` is a single IP packet.
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Largest IP packet the link can carry.
+    fn mtu(&self) -> usize;
+
+    /// Whether the link already verified the IP and TCP checksums of the packets `recv` returns
+    /// and dropped the bad ones, so the stack doesn't have to.
+    fn verifies_checksums(&self) -> bool {
+        false
+    }
+
+    /// Whether the link computes the TCP checksums of the packets it sends.
+    ///
+    /// If so, the stack leaves the folded sum of the pseudo header in the checksum field for the
+    /// link to add the segment to, like Linux's `CHECKSUM_PARTIAL`.
+    fn fills_checksums(&self) -> bool {
+        false
+    }
+
+    /// How many packets the link dropped so far because they carried something other than IPv4
+    /// or IPv6, if it looks.
+    fn unknown_protocol(&self) -> u64 {
+        0
+    }
+
+    /// A way for other threads to cut `recv` short, if the link has one.
+    ///
+    /// Without it, the stack only gets to what other threads queued up for it, like data written
+    /// to a stream, once `recv` times out.
+    fn waker(&self) -> Option<Waker> {
+        None
+    }
+}
+
+/// Makes the `recv` of a device return early, from another thread: the one waiting at the time,
+/// or else the next one.
+#[derive(Clone)]
+pub struct Waker(Arc<dyn Fn() + Send + Sync>);
+
+impl Waker {
+    pub fn new(wake: impl Fn() + Send + Sync + 'static) -> Self {
+        Waker(Arc::new(wake))
+    }
+
+    pub fn wake(&self) {
+        (self.0)()
+    }
+}
+
+impl<D: Device + ?Sized> Device for Box<D> {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        (**self).recv(buf, deadline)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (**self).send(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        (**self).mtu()
+    }
+
+    fn verifies_checksums(&self) -> bool {
+        (**self).verifies_checksums()
+    }
+
+    fn fills_checksums(&self) -> bool {
+        (**self).fills_checksums()
+    }
+
+    fn unknown_protocol(&self) -> u64 {
+        (**self).unknown_protocol()
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        (**self).waker()
+    }
+}
+
+/// A Linux tun device, or one queue of a multi-queue tun device.
+///
+/// Opened with packet information, every packet comes with 4 bytes of flags and protocol in
+/// front, which its `mtu` counts too. Interfaces built with `InterfaceBuilder::packet_info`
+/// take care of them. Creating it needs `CAP_NET_ADMIN`.
+pub struct Tun {
+    file: File,
+    mtu: usize,
+    wake: Arc<EventFd>,
+}
+
+/// `struct ifreq` of `<linux/if.h>`, as far as `TUNSETIFF` looks at it
+#[repr(C)]
+struct IfReq {
+    name: [u8; 16],
+    flags: nix::libc::c_short,
+    _union: [u8; 22],
+}
+
+nix::ioctl_write_ptr_bad!(tun_set_iff, nix::request_code_write!(b'T', 202, std::mem::size_of::<nix::libc::c_int>()), IfReq);
+
+impl Tun {
+    /// Open (or create) the tun device `name`. `mtu` must match what the host side of the
+    /// device is configured with.
+    pub fn open(name: &str, mtu: usize) -> io::Result<Self> {
+        Tun::attach(name, nix::libc::IFF_NO_PI, mtu)
+    }
+
+    /// Open (or create) the tun device `name` with packet information.
+    pub fn open_with_packet_info(name: &str, mtu: usize) -> io::Result<Self> {
+        Tun::attach(name, 0, mtu + PACKET_INFO_LEN)
+    }
+
+    /// Open (or create) the multi-queue tun device `name` with `queues` queues. The kernel
+    /// hands the packets of a flow to the queue that last sent packets of it.
+    pub fn open_queues(name: &str, mtu: usize, queues: usize, packet_info: bool) -> io::Result<Vec<Self>> {
+        let (flags, mtu) = match packet_info {
+            true => (0, mtu + PACKET_INFO_LEN),
+            false => (nix::libc::IFF_NO_PI, mtu),
+        };
+        (0..queues).map(|_| Tun::attach(name, flags | nix::libc::IFF_MULTI_QUEUE, mtu)).collect()
+    }
+
+    fn attach(name: &str, flags: nix::libc::c_int, mtu: usize) -> io::Result<Self> {
+        let mut req = IfReq {
+            name: [0; 16],
+            flags: (nix::libc::IFF_TUN | flags) as nix::libc::c_short,
+            _union: [0; 22],
+        };
+        // leave room for the terminating NUL
+        if name.len() >= req.name.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "device name is too long"));
+        }
+        req.name[..name.len()].copy_from_slice(name.as_bytes());
+
+        let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+        unsafe { tun_set_iff(file.as_raw_fd(), &req) }.map_err(nix_error)?;
+        Ok(Tun { file, mtu, wake: EventFd::new()? })
+    }
+}
+
+impl Device for Tun {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        recv_until(self.file.as_raw_fd(), &self.wake, buf, deadline)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        let wake = self.wake.clone();
+        Some(Waker::new(move || wake.signal()))
+    }
+}
+
+/// A Linux tap device, opened without packet information.
+///
+/// It carries whole Ethernet frames, so its `mtu` counts the 14 bytes of Ethernet header on top
+/// of the MTU of the device. Interfaces built with `InterfaceBuilder::ethernet` do the framing.
+/// Creating it needs `CAP_NET_ADMIN`.
+pub struct Tap {
+    iface: tun_tap::Iface,
+    mtu: usize,
+    wake: Arc<EventFd>,
+}
+
+impl Tap {
+    /// Open (or create) the tap device `name`. `mtu` must match what the host side of the
+    /// device is configured with.
+    pub fn open(name: &str, mtu: usize) -> io::Result<Self> {
+        let iface = tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tap)?;
+        Ok(Tap { iface, mtu, wake: EventFd::new()? })
+    }
+}
+
+impl Device for Tap {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        recv_until(self.iface.as_raw_fd(), &self.wake, buf, deadline)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.iface.send(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu + 14
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        let wake = self.wake.clone();
+        Some(Waker::new(move || wake.signal()))
+    }
+}
+
+/// Bytes of flags and protocol in front of the packets of a tun device with packet information
+const PACKET_INFO_LEN: usize = 4;
+const IPV4: u16 = 0x0800;
+const IPV6: u16 = 0x86dd;
+
+/// A link whose packets come with the flags and protocol of a tun device opened with packet
+/// information, which the stack sees as one of bare IP packets
+pub(crate) struct PacketInfo<D> {
+    inner: D,
+    /// packets dropped because they carried neither IPv4 nor IPv6
+    unknown: u64,
+    packet: Vec<u8>,
+}
+
+impl<D: Device> PacketInfo<D> {
+    pub(crate) fn new(inner: D) -> Self {
+        let packet = vec![0; inner.mtu()];
+        PacketInfo { inner, unknown: 0, packet }
+    }
+}
+
+impl<D: Device> Device for PacketInfo<D> {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        loop {
+            let n = match self.inner.recv(&mut self.packet, deadline)? {
+                Some(n) => n,
+                None => return Ok(None),
+            };
+            if n < PACKET_INFO_LEN {
+                continue;
+            }
+            // the protocol is an EtherType
+            match u16::from_be_bytes([self.packet[2], self.packet[3]]) {
+                IPV4 | IPV6 => {
+                    let packet = &self.packet[PACKET_INFO_LEN..n];
+                    let n = std::cmp::min(packet.len(), buf.len());
+                    buf[..n].copy_from_slice(&packet[..n]);
+                    return Ok(Some(n));
+                }
+                _ => self.unknown += 1,
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let proto = match buf.first().map(|b| b >> 4) {
+            Some(4) => IPV4,
+            Some(6) => IPV6,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not an IP packet")),
+        };
+        let mut packet = Vec::with_capacity(PACKET_INFO_LEN + buf.len());
+        // no flags
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&proto.to_be_bytes());
+        packet.extend_from_slice(buf);
+        self.inner.send(&packet)?;
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu().saturating_sub(PACKET_INFO_LEN)
+    }
+
+    fn verifies_checksums(&self) -> bool {
+        self.inner.verifies_checksums()
+    }
+
+    fn fills_checksums(&self) -> bool {
+        self.inner.fills_checksums()
+    }
+
+    fn unknown_protocol(&self) -> u64 {
+        self.inner.unknown_protocol() + self.unknown
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+}
+
+/// The `io::Error` of a failed system call
+pub(crate) fn nix_error(e: nix::Error) -> io::Error {
+    e.as_errno().map(io::Error::from).unwrap_or_else(|| io::Error::other(e))
+}
+
+/// Receive from the tun or tap device `fd`, waiting no later than `deadline`, or until `wake`
+/// is signaled
+fn recv_until(fd: RawFd, wake: &EventFd, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    // round up so we don't spin on sub-millisecond timeouts
+    let timeout_ms = timeout.as_micros().div_ceil(1000);
+    let timeout_ms = std::cmp::min(timeout_ms, i32::MAX as u128) as i32;
+
+    let mut pfd = [
+        nix::poll::PollFd::new(fd, nix::poll::PollFlags::POLLIN),
+        nix::poll::PollFd::new(wake.fd(), nix::poll::PollFlags::POLLIN),
+    ];
+    match nix::poll::poll(&mut pfd[..], timeout_ms) {
+        Ok(_) => {}
+        Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => return Ok(None),
+        Err(e) => return Err(nix_error(e)),
+    }
+    let readable = |pfd: &nix::poll::PollFd| pfd.revents().is_some_and(|r| r.contains(nix::poll::PollFlags::POLLIN));
+    if readable(&pfd[1]) {
+        wake.reset();
+    }
+    if !readable(&pfd[0]) {
+        return Ok(None);
+    }
+
+    nix::unistd::read(fd, buf).map(Some).map_err(nix_error)
+}
+
+/// Packets travelling in one direction of a `pipe`
+#[derive(Default)]
+struct Queue {
+    packets: Mutex<VecDeque<Vec<u8>>>,
+    var: Condvar,
+    /// set by the waker of the receiving end, and cleared by the `recv` it cut short
+    woken: AtomicBool,
+}
+
+impl Queue {
+    fn wake(&self) {
+        // with the lock, so a `recv` about to wait doesn't miss it
+        let _packets = self.packets.lock().unwrap();
+        self.woken.store(true, Ordering::Relaxed);
+        self.var.notify_all();
+    }
+}
+
+/// One end of an in-memory link, made by `pipe`.
+///
+/// Besides giving it to an `Interface`, tests can drive an end by hand through its `Device`
+/// methods to play the peer.
+pub struct Pipe {
+    tx: Arc<Queue>,
+    rx: Arc<Queue>,
+    mtu: usize,
+}
+
+/// Make a pair of devices where whatever is sent on one end is received on the other.
+///
+/// Packets are never lost, reordered or delayed. Packets sent after the other end is dropped go
+/// nowhere.
+pub fn pipe(mtu: usize) -> (Pipe, Pipe) {
+    let a = Arc::new(Queue::default());
+    let b = Arc::new(Queue::default());
+    (
+        Pipe { tx: a.clone(), rx: b.clone(), mtu },
+        Pipe { tx: b, rx: a, mtu },
+    )
+}
+
+impl Device for Pipe {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        let mut packets = self.rx.packets.lock().unwrap();
+        loop {
+            if let Some(packet) = packets.pop_front() {
+                let n = std::cmp::min(buf.len(), packet.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                return Ok(Some(n));
+            }
+
+            let now = Instant::now();
+            if now >= deadline || self.rx.woken.swap(false, Ordering::Relaxed) {
+                return Ok(None);
+            }
+            packets = self.rx.var.wait_timeout(packets, deadline - now).unwrap().0;
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.mtu {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "packet is larger than the MTU"));
+        }
+
+        self.tx.packets.lock().unwrap().push_back(buf.to_vec());
+        self.tx.var.notify_one();
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        let rx = self.rx.clone();
+        Some(Waker::new(move || rx.wake()))
+    }
+}
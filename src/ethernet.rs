@@ -0,0 +1,502 @@
+//! Ethernet II framing (RFC 894) for links that carry whole frames, like a tap device, with ARP
+//! (RFC 826) and IPv6 neighbor discovery (RFC 4861) to learn where our neighbors are.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use crate::device::{Device, Waker};
+use crate::{checksum, icmp, ip};
+
+/// Bytes of Ethernet header in front of the payload
+const HEADER_LEN: usize = 14;
+/// Shorter frames are padded to this length, not counting the frame check sequence
+const MIN_FRAME: usize = 60;
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+// EtherTypes of what the frames carry
+const IPV4: u16 = 0x0800;
+const ARP: u16 = 0x0806;
+const IPV6: u16 = 0x86dd;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+/// Options of neighbor discovery messages with the link-layer address of the sender, and that
+/// of the target
+const SOURCE_LINK_ADDR: u8 = 1;
+const TARGET_LINK_ADDR: u8 = 2;
+/// Flags of an advertisement: it answers a solicitation, and overrides what the cache holds
+const SOLICITED: u8 = 0x40;
+const OVERRIDE: u8 = 0x20;
+/// Neighbor discovery messages come with this hop limit, which no router leaves (RFC 4861 S7.1)
+const NDP_HOP_LIMIT: u8 = 255;
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// How long we use a neighbor's address before we ask for it again
+const REACHABLE: Duration = Duration::from_secs(60);
+/// How long we wait for an answer before we ask again, which is at most once a second
+/// (RFC 1122 S2.3.2.1)
+const RETRY: Duration = Duration::from_secs(1);
+/// Requests that go unanswered before we give up on a neighbor (RFC 4861 S10)
+const MAX_REQUESTS: u32 = 3;
+/// Packets we hold for a neighbor while we wait for its address
+const MAX_WAITING: usize = 16;
+
+enum Neighbor {
+    /// we asked for its address, and hold on to the packets to it meanwhile
+    Incomplete { requests: u32, retry_at: Instant, waiting: VecDeque<Vec<u8>> },
+    Reachable { mac: [u8; 6], until: Instant },
+}
+
+/// A link of Ethernet frames, which the stack sees as one of IP packets
+pub(crate) struct Ethernet<D> {
+    inner: D,
+    mac: [u8; 6],
+    local: (Ipv4Addr, u8),
+    local6: Option<(Ipv6Addr, u8)>,
+    gateway: Option<Ipv4Addr>,
+    gateway6: Option<Ipv6Addr>,
+    /// link-layer addresses of the hosts we talk to directly, by IP address
+    neighbors: HashMap<IpAddr, Neighbor>,
+    /// whether we announced our addresses on the link yet
+    announced: bool,
+    /// frames dropped because they carried neither IP nor ARP
+    unknown: u64,
+    frame: Vec<u8>,
+}
+
+impl<D: Device> Ethernet<D> {
+    pub(crate) fn new(
+        inner: D,
+        mac: [u8; 6],
+        local: (Ipv4Addr, u8),
+        local6: Option<(Ipv6Addr, u8)>,
+        gateway: Option<Ipv4Addr>,
+        gateway6: Option<Ipv6Addr>) -> Self {
+        let frame = vec![0; inner.mtu()];
+        Ethernet {
+            inner,
+            mac,
+            local,
+            local6,
+            gateway,
+            gateway6,
+            neighbors: HashMap::new(),
+            announced: false,
+            unknown: 0,
+            frame,
+        }
+    }
+
+    /// Tell the link who we are, so neighbors that knew another host at our address update
+    /// their caches: a gratuitous ARP, and an unsolicited advertisement for IPv6 (RFC 5227 S3,
+    /// RFC 4861 S7.2.6).
+    fn announce(&mut self) -> io::Result<()> {
+        let addr = self.local.0;
+        self.send_arp(BROADCAST, ARP_REQUEST, [0; 6], addr)?;
+        if let Some((addr6, _)) = self.local6 {
+            let advertisement = self.advertisement(addr6, OVERRIDE);
+            let packet = self.ndp(ALL_NODES, advertisement);
+            self.send_frame(multicast_mac(ALL_NODES), IPV6, &packet)?;
+        }
+        Ok(())
+    }
+
+    /// Ask again for the addresses nobody answered for yet, and forget the neighbors we haven't
+    /// heard from for too long. Returns when to come back.
+    fn on_timer(&mut self, now: Instant) -> io::Result<Option<Instant>> {
+        if !self.announced {
+            self.announced = true;
+            self.announce()?;
+        }
+
+        let mut ask = Vec::new();
+        self.neighbors.retain(|&addr, n| match n {
+            Neighbor::Reachable { until, .. } => *until > now,
+            Neighbor::Incomplete { retry_at, .. } if *retry_at > now => true,
+            // nobody answers, so what waits for it goes nowhere
+            Neighbor::Incomplete { requests, .. } if *requests >= MAX_REQUESTS => false,
+            Neighbor::Incomplete { requests, retry_at, .. } => {
+                *requests += 1;
+                *retry_at = now + RETRY;
+                ask.push(addr);
+                true
+            }
+        });
+        for addr in ask {
+            self.solicit(addr)?;
+        }
+
+        Ok(self.neighbors.values().filter_map(|n| match n {
+            Neighbor::Incomplete { retry_at, .. } => Some(*retry_at),
+            Neighbor::Reachable { .. } => None,
+        }).min())
+    }
+
+    /// Where packets to `dst` go on the link: straight to a link-layer address, or to the
+    /// neighbor whose address we have to look up
+    fn next_hop(&self, dst: IpAddr) -> Result<[u8; 6], IpAddr> {
+        match dst {
+            IpAddr::V4(dst) => {
+                if dst.is_broadcast() {
+                    return Ok(BROADCAST);
+                }
+                let (addr, prefix) = self.local;
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                match self.gateway {
+                    Some(gateway) if u32::from(dst) & mask != u32::from(addr) & mask => Err(gateway.into()),
+                    _ => Err(dst.into()),
+                }
+            }
+            IpAddr::V6(dst) => {
+                if dst.is_multicast() {
+                    return Ok(multicast_mac(dst));
+                }
+                let on_link = match self.local6 {
+                    Some((addr, prefix)) => {
+                        let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                        u128::from(dst) & mask == u128::from(addr) & mask
+                    }
+                    None => false,
+                };
+                // link-local addresses are on the link, by definition
+                let link_local = dst.segments()[0] & 0xffc0 == 0xfe80;
+                match self.gateway6 {
+                    Some(gateway) if !on_link && !link_local => Err(gateway.into()),
+                    _ => Err(dst.into()),
+                }
+            }
+        }
+    }
+
+    /// Send the IP packet `packet` to the neighbor it is for, or hold it until we know where
+    /// that is.
+    fn send_ip(&mut self, now: Instant, packet: &[u8]) -> io::Result<()> {
+        let (dst, ethertype) = match destination(packet) {
+            Some(dst) => dst,
+            None => return Ok(()),
+        };
+        let neighbor = match self.next_hop(dst) {
+            Ok(mac) => return self.send_frame(mac, ethertype, packet),
+            Err(neighbor) => neighbor,
+        };
+        match self.neighbors.get_mut(&neighbor) {
+            Some(Neighbor::Reachable { mac, .. }) => {
+                let mac = *mac;
+                self.send_frame(mac, ethertype, packet)
+            }
+            Some(Neighbor::Incomplete { waiting, .. }) => {
+                if waiting.len() < MAX_WAITING {
+                    waiting.push_back(packet.to_vec());
+                }
+                Ok(())
+            }
+            None => {
+                self.neighbors.insert(neighbor, Neighbor::Incomplete {
+                    requests: 1,
+                    retry_at: now + RETRY,
+                    waiting: VecDeque::from(vec![packet.to_vec()]),
+                });
+                self.solicit(neighbor)
+            }
+        }
+    }
+
+    /// `mac` is where `addr` is. Only neighbors we already know of or asked for are updated,
+    /// unless `create` is set. Packets that waited for the address go out.
+    fn learn(&mut self, now: Instant, addr: IpAddr, mac: [u8; 6], create: bool) -> io::Result<()> {
+        if !create && !self.neighbors.contains_key(&addr) {
+            return Ok(());
+        }
+        let old = self.neighbors.insert(addr, Neighbor::Reachable { mac, until: now + REACHABLE });
+        if let Some(Neighbor::Incomplete { waiting, .. }) = old {
+            for packet in waiting {
+                let ethertype = if addr.is_ipv4() { IPV4 } else { IPV6 };
+                self.send_frame(mac, ethertype, &packet)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the link for the link-layer address of `addr`.
+    fn solicit(&mut self, addr: IpAddr) -> io::Result<()> {
+        match addr {
+            IpAddr::V4(addr) => self.send_arp(BROADCAST, ARP_REQUEST, [0; 6], addr),
+            IpAddr::V6(addr) => {
+                let mut message = vec![NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+                message.extend_from_slice(&addr.octets());
+                message.extend_from_slice(&[SOURCE_LINK_ADDR, 1]);
+                message.extend_from_slice(&self.mac);
+                let group = solicited_node(addr);
+                let packet = self.ndp(group, message);
+                self.send_frame(multicast_mac(group), IPV6, &packet)
+            }
+        }
+    }
+
+    fn on_frame(&mut self, now: Instant, frame: &[u8], buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let eth = match etherparse::Ethernet2HeaderSlice::from_slice(frame) {
+            Ok(eth) => eth,
+            Err(_) => return Ok(None),
+        };
+        let mut dst = [0; 6];
+        dst.copy_from_slice(eth.destination());
+        if !self.wants(dst) {
+            // for another host on the segment
+            return Ok(None);
+        }
+        let payload = &frame[HEADER_LEN..];
+        match eth.ether_type() {
+            ARP => {
+                self.on_arp(now, payload)?;
+                Ok(None)
+            }
+            IPV6 if self.on_ndp(now, payload)? => Ok(None),
+            IPV4 | IPV6 => {
+                let n = std::cmp::min(payload.len(), buf.len());
+                buf[..n].copy_from_slice(&payload[..n]);
+                Ok(Some(n))
+            }
+            _ => {
+                self.unknown += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Whether frames to `dst` are for us
+    fn wants(&self, dst: [u8; 6]) -> bool {
+        if dst == self.mac || dst == BROADCAST {
+            return true;
+        }
+        match self.local6 {
+            Some((addr, _)) => dst == multicast_mac(ALL_NODES) || dst == multicast_mac(solicited_node(addr)),
+            None => false,
+        }
+    }
+
+    /// Handle an ARP packet, following the algorithm of RFC 826.
+    fn on_arp(&mut self, now: Instant, packet: &[u8]) -> io::Result<()> {
+        // Ethernet and IPv4 addresses are all we resolve
+        if packet.len() < 28 || packet[..6] != [0, 1, 8, 0, 6, 4] {
+            return Ok(());
+        }
+        let op = u16::from_be_bytes([packet[6], packet[7]]);
+        let mut sha = [0; 6];
+        sha.copy_from_slice(&packet[8..14]);
+        let spa = Ipv4Addr::new(packet[14], packet[15], packet[16], packet[17]);
+        let tpa = Ipv4Addr::new(packet[24], packet[25], packet[26], packet[27]);
+        let ours = self.local.0;
+        // probes of hosts that don't have an address yet (RFC 5227), and other hosts that claim
+        // ours, teach us nothing
+        if spa.is_unspecified() || spa == ours {
+            return Ok(());
+        }
+
+        self.learn(now, spa.into(), sha, tpa == ours)?;
+        if tpa == ours && op == ARP_REQUEST {
+            self.send_arp(sha, ARP_REPLY, sha, spa)?;
+        }
+        Ok(())
+    }
+
+    /// Send an ARP packet of type `op` to `dst`, about the IP address `tpa` at `tha`. Gratuitous
+    /// ARPs ask for our own address.
+    fn send_arp(&mut self, dst: [u8; 6], op: u16, tha: [u8; 6], tpa: Ipv4Addr) -> io::Result<()> {
+        let mut packet = vec![0, 1, 8, 0, 6, 4];
+        packet.extend_from_slice(&op.to_be_bytes());
+        packet.extend_from_slice(&self.mac);
+        packet.extend_from_slice(&self.local.0.octets());
+        packet.extend_from_slice(&tha);
+        packet.extend_from_slice(&tpa.octets());
+        self.send_frame(dst, ARP, &packet)
+    }
+
+    /// Handle `packet` if it is a neighbor discovery message. Returns whether it was.
+    fn on_ndp(&mut self, now: Instant, packet: &[u8]) -> io::Result<bool> {
+        let addr = match self.local6 {
+            Some((addr, _)) => addr,
+            None => return Ok(false),
+        };
+        let iph = match etherparse::Ipv6HeaderSlice::from_slice(packet) {
+            Ok(iph) => iph,
+            Err(_) => return Ok(false),
+        };
+        let message = &packet[iph.slice().len()..];
+        if iph.next_header() != icmp::PROTOCOL6 || message.len() < 24 {
+            return Ok(false);
+        }
+        if message[0] != NEIGHBOR_SOLICITATION && message[0] != NEIGHBOR_ADVERTISEMENT {
+            return Ok(false);
+        }
+        let message = &message[..std::cmp::min(message.len(), iph.payload_length() as usize)];
+        let (src, dst) = (IpAddr::V6(iph.source_addr()), IpAddr::V6(iph.destination_addr()));
+        if iph.hop_limit() != NDP_HOP_LIMIT || message[1] != 0 || !checksum::transport_ok(src, dst, icmp::PROTOCOL6, message) {
+            return Ok(true);
+        }
+
+        let mut target = [0; 16];
+        target.copy_from_slice(&message[8..24]);
+        let target = Ipv6Addr::from(target);
+        if message[0] == NEIGHBOR_SOLICITATION {
+            if target != addr {
+                return Ok(true);
+            }
+            if let (Some(mac), false) = (link_addr(&message[24..], SOURCE_LINK_ADDR), iph.source_addr().is_unspecified()) {
+                self.learn(now, src, mac, true)?;
+            }
+            // hosts checking that nobody has their address yet hear back on the all-nodes group
+            // (RFC 4861 S7.2.4)
+            let (to, flags) = match iph.source_addr() {
+                a if a.is_unspecified() => (ALL_NODES, OVERRIDE),
+                a => (a, SOLICITED | OVERRIDE),
+            };
+            let advertisement = self.advertisement(addr, flags);
+            let packet = self.ndp(to, advertisement);
+            self.send_ip(now, &packet)?;
+        } else if let Some(mac) = link_addr(&message[24..], TARGET_LINK_ADDR) {
+            self.learn(now, target.into(), mac, false)?;
+        }
+        Ok(true)
+    }
+
+    /// A neighbor advertisement that `target` is at our link-layer address
+    fn advertisement(&self, target: Ipv6Addr, flags: u8) -> Vec<u8> {
+        let mut message = vec![NEIGHBOR_ADVERTISEMENT, 0, 0, 0, flags, 0, 0, 0];
+        message.extend_from_slice(&target.octets());
+        message.extend_from_slice(&[TARGET_LINK_ADDR, 1]);
+        message.extend_from_slice(&self.mac);
+        message
+    }
+
+    /// Put the checksum into the neighbor discovery `message` and send it to `dst`, as an IP
+    /// packet
+    fn ndp(&self, dst: Ipv6Addr, mut message: Vec<u8>) -> Vec<u8> {
+        let (src, _) = self.local6.expect("neighbor discovery needs an IPv6 address");
+        let ip = ip::Header::new(NDP_HOP_LIMIT, icmp::PROTOCOL6, src.into(), dst.into());
+        let mut sum = ip.pseudo_header(message.len());
+        sum.add(&message);
+        message[2..4].copy_from_slice(&sum.finish().to_be_bytes());
+        ip.packet(&message)
+    }
+
+    fn send_frame(&mut self, dst: [u8; 6], ethertype: u16, payload: &[u8]) -> io::Result<()> {
+        let eth = etherparse::Ethernet2Header {
+            destination: dst,
+            source: self.mac,
+            ether_type: ethertype,
+        };
+        let mut frame = Vec::with_capacity(std::cmp::max(HEADER_LEN + payload.len(), MIN_FRAME));
+        eth.write(&mut frame).expect("Can't write ethernet header");
+        frame.extend_from_slice(payload);
+        frame.resize(std::cmp::max(frame.len(), MIN_FRAME), 0);
+        self.inner.send(&frame)?;
+        Ok(())
+    }
+}
+
+impl<D: Device> Device for Ethernet<D> {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        loop {
+            let now = Instant::now();
+            let retry_at = self.on_timer(now)?;
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            let wake = retry_at.map_or(deadline, |at| std::cmp::min(at, deadline));
+            let mut frame = std::mem::take(&mut self.frame);
+            let received = match self.inner.recv(&mut frame, wake) {
+                Ok(Some(n)) => self.on_frame(Instant::now(), &frame[..n], buf),
+                Ok(None) if Instant::now() < wake => {
+                    // cut short, maybe by our waker: let the stack look
+                    self.frame = frame;
+                    return Ok(None);
+                }
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            };
+            self.frame = frame;
+            if let Some(n) = received? {
+                return Ok(Some(n));
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        self.on_timer(now)?;
+        self.send_ip(now, buf)?;
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu().saturating_sub(HEADER_LEN)
+    }
+
+    fn verifies_checksums(&self) -> bool {
+        self.inner.verifies_checksums()
+    }
+
+    fn fills_checksums(&self) -> bool {
+        self.inner.fills_checksums()
+    }
+
+    fn unknown_protocol(&self) -> u64 {
+        self.inner.unknown_protocol() + self.unknown
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+}
+
+/// The destination of the IP packet `packet`, and the EtherType it goes out with
+fn destination(packet: &[u8]) -> Option<(IpAddr, u16)> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+            Some((dst.into(), IPV4))
+        }
+        6 if packet.len() >= 40 => {
+            let mut dst = [0; 16];
+            dst.copy_from_slice(&packet[24..40]);
+            Some((Ipv6Addr::from(dst).into(), IPV6))
+        }
+        _ => None,
+    }
+}
+
+/// The link-layer address IPv6 multicasts to `group` go to (RFC 2464 S7)
+fn multicast_mac(group: Ipv6Addr) -> [u8; 6] {
+    let o = group.octets();
+    [0x33, 0x33, o[12], o[13], o[14], o[15]]
+}
+
+/// The group that solicitations for `addr` go to (RFC 4291 S2.7.1)
+fn solicited_node(addr: Ipv6Addr) -> Ipv6Addr {
+    let o = addr.octets();
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 1, 0xff00 | o[13] as u16, u16::from_be_bytes([o[14], o[15]]))
+}
+
+/// The link-layer address in the option of type `kind` among `options`
+fn link_addr(mut options: &[u8], kind: u8) -> Option<[u8; 6]> {
+    while options.len() >= 2 {
+        // counted in 8 byte units, and never 0 (RFC 4861 S4.6)
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        if options[0] == kind && len >= 8 {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&options[2..8]);
+            return Some(mac);
+        }
+        options = &options[len..];
+    }
+    None
+}
@@ -0,0 +1,117 @@
+//! File descriptors that tell event loops like epoll or mio when a stream or listener may be
+//! ready, so they can be waited on alongside kernel sockets.
+//!
+//! Every stream and listener can have an eventfd, which becomes readable whenever what the
+//! stream or listener is ready for changes, and is reset once a read, write or accept on it
+//! fails with `WouldBlock`. So once it is readable, try everything you wait for until it fails
+//! with `WouldBlock`.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use nix::sys::eventfd::{eventfd, EfdFlags};
+
+use crate::device::nix_error;
+use crate::{TcpListener, TcpStream};
+
+pub(crate) struct EventFd(RawFd);
+
+impl EventFd {
+    /// A new eventfd, which starts out signaled so the first look at the stream happens right
+    /// away
+    pub(crate) fn new() -> io::Result<Arc<Self>> {
+        let fd = eventfd(1, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC).map_err(nix_error)?;
+        Ok(Arc::new(EventFd(fd)))
+    }
+
+    /// The descriptor of the eventfd in `slot`, made on first use
+    fn of(slot: &mut Option<Arc<Self>>) -> io::Result<RawFd> {
+        if let Some(fd) = slot {
+            return Ok(fd.0);
+        }
+        let fd = EventFd::new()?;
+        let raw = fd.0;
+        *slot = Some(fd);
+        Ok(raw)
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.0
+    }
+
+    pub(crate) fn signal(&self) {
+        // only fails if the counter is about to overflow, and then it is readable anyway
+        let _ = nix::unistd::write(self.0, &1u64.to_ne_bytes());
+    }
+
+    pub(crate) fn reset(&self) {
+        // fails with EAGAIN if it wasn't signaled
+        let _ = nix::unistd::read(self.0, &mut [0u8; 8]);
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.0);
+    }
+}
+
+impl TcpStream {
+    /// An eventfd that becomes readable when the stream may be ready, for event loops like
+    /// epoll. It stays open as long as the stream.
+    pub fn event_fd(&self) -> io::Result<RawFd> {
+        let mut shard = self.h.shard(&self.quad);
+        EventFd::of(&mut shard.wait_list(self.quad).fd)
+    }
+}
+
+impl TcpListener {
+    /// An eventfd that becomes readable when a connection may be pending, for event loops like
+    /// epoll. It stays open as long as the listener.
+    pub fn event_fd(&self) -> io::Result<RawFd> {
+        let mut cm = self.h.manager.lock().unwrap();
+        EventFd::of(&mut cm.listener(self.port).fd)
+    }
+}
+
+/// Streams and listeners are registered as their eventfd, which is only ever readable, whatever
+/// the interest.
+#[cfg(feature = "mio")]
+mod source {
+    use std::io;
+
+    use mio::event::Source;
+    use mio::unix::SourceFd;
+    use mio::{Interest, Registry, Token};
+
+    use crate::{TcpListener, TcpStream};
+
+    impl Source for TcpStream {
+        fn register(&mut self, registry: &Registry, token: Token, _interests: Interest) -> io::Result<()> {
+            SourceFd(&self.event_fd()?).register(registry, token, Interest::READABLE)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, _interests: Interest) -> io::Result<()> {
+            SourceFd(&self.event_fd()?).reregister(registry, token, Interest::READABLE)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            SourceFd(&self.event_fd()?).deregister(registry)
+        }
+    }
+
+    impl Source for TcpListener {
+        fn register(&mut self, registry: &Registry, token: Token, _interests: Interest) -> io::Result<()> {
+            SourceFd(&self.event_fd()?).register(registry, token, Interest::READABLE)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, _interests: Interest) -> io::Result<()> {
+            SourceFd(&self.event_fd()?).reregister(registry, token, Interest::READABLE)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            SourceFd(&self.event_fd()?).deregister(registry)
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! ICMP messages (RFC 792), and their ICMPv6 counterparts (RFC 4443).
+
+use std::io;
+use std::net::IpAddr;
+
+use crate::checksum::Checksum;
+use crate::ip;
+
+/// ICMP's number in the protocol field of the IP header
+pub(crate) const PROTOCOL: u8 = 1;
+/// ICMPv6's number in the next header field of the IPv6 header
+pub(crate) const PROTOCOL6: u8 = 58;
+
+const ECHO_REPLY: u8 = 0;
+const DESTINATION_UNREACHABLE: u8 = 3;
+const ECHO_REQUEST: u8 = 8;
+const TIME_EXCEEDED: u8 = 11;
+
+const DESTINATION_UNREACHABLE6: u8 = 1;
+const PACKET_TOO_BIG6: u8 = 2;
+const TIME_EXCEEDED6: u8 = 3;
+const ECHO_REQUEST6: u8 = 128;
+const ECHO_REPLY6: u8 = 129;
+
+/// Code of a destination unreachable message about a datagram that needed fragmentation but
+/// had DF set (RFC 1191 S4)
+const FRAGMENTATION_NEEDED: u8 = 4;
+/// Code of a destination unreachable message about a datagram to a port nobody listens on
+const PORT_UNREACHABLE: u8 = 3;
+/// The same for ICMPv6
+const PORT_UNREACHABLE6: u8 = 4;
+/// Error messages are kept within the size every host must accept (RFC 1812 S4.3.2.3)
+const MAX_ERROR_LEN: usize = 576;
+/// and ICMPv6 ones within the minimum IPv6 MTU (RFC 4443 S2.4)
+const MAX_ERROR_LEN6: usize = 1280;
+
+pub(crate) enum Message<'a> {
+    /// Someone pings us
+    EchoRequest,
+    /// A router dropped `original` because it was larger than the MTU of the next hop. Routers
+    /// older than RFC 1191 leave the MTU at 0.
+    TooBig { next_hop_mtu: u32, original: &'a [u8] },
+    /// The datagram that starts with `original`, its IP header and at least 8 bytes after it,
+    /// could not be delivered. Hard errors say that trying again won't help (RFC 1122 S4.2.3.9).
+    Error { kind: io::ErrorKind, hard: bool, original: &'a [u8] },
+    /// anything we don't handle
+    Other,
+}
+
+/// Read the ICMP or ICMPv6 message `packet` carries, or `None` if it is too short to be one.
+pub(crate) fn parse<'a>(packet: &ip::Packet<'a>) -> Option<Message<'a>> {
+    let message = packet.payload;
+    if message.len() < 8 {
+        return None;
+    }
+    match packet.src {
+        IpAddr::V4(_) => Some(parse4(message)),
+        IpAddr::V6(_) => Some(parse6(message)),
+    }
+}
+
+fn parse4(message: &[u8]) -> Message<'_> {
+    let original = &message[8..];
+    match (message[0], message[1]) {
+        (ECHO_REQUEST, 0) => Message::EchoRequest,
+        (DESTINATION_UNREACHABLE, FRAGMENTATION_NEEDED) => Message::TooBig {
+            next_hop_mtu: u16::from_be_bytes([message[6], message[7]]) as u32,
+            original,
+        },
+        (DESTINATION_UNREACHABLE, code) => {
+            let (kind, hard) = match code {
+                // net unreachable, unknown or not reachable for the type of service
+                0 | 6 | 11 => (io::ErrorKind::NetworkUnreachable, false),
+                // protocol or port unreachable: the host is there but won't talk to us
+                2 | 3 => (io::ErrorKind::ConnectionRefused, true),
+                // communication administratively prohibited (RFC 1812 S5.2.7.1)
+                9 | 10 | 13 => (io::ErrorKind::HostUnreachable, true),
+                // host unreachable, unknown, isolated, and the like
+                _ => (io::ErrorKind::HostUnreachable, false),
+            };
+            Message::Error { kind, hard, original }
+        }
+        // TTL or fragment reassembly time exceeded, which a route change may fix
+        (TIME_EXCEEDED, _) => Message::Error {
+            kind: io::ErrorKind::HostUnreachable,
+            hard: false,
+            original,
+        },
+        _ => Message::Other,
+    }
+}
+
+fn parse6(message: &[u8]) -> Message<'_> {
+    let original = &message[8..];
+    match (message[0], message[1]) {
+        (ECHO_REQUEST6, 0) => Message::EchoRequest,
+        (PACKET_TOO_BIG6, _) => Message::TooBig {
+            next_hop_mtu: u32::from_be_bytes([message[4], message[5], message[6], message[7]]),
+            original,
+        },
+        (DESTINATION_UNREACHABLE6, code) => {
+            let (kind, hard) = match code {
+                // no route to destination
+                0 => (io::ErrorKind::NetworkUnreachable, false),
+                // port unreachable: the host is there but won't talk to us
+                PORT_UNREACHABLE6 => (io::ErrorKind::ConnectionRefused, true),
+                // administratively prohibited, failed policy or reject route
+                1 | 5 | 6 => (io::ErrorKind::HostUnreachable, true),
+                // beyond the scope of the source address, address unreachable, and the like
+                _ => (io::ErrorKind::HostUnreachable, false),
+            };
+            Message::Error { kind, hard, original }
+        }
+        // hop limit or fragment reassembly time exceeded
+        (TIME_EXCEEDED6, _) => Message::Error {
+            kind: io::ErrorKind::HostUnreachable,
+            hard: false,
+            original,
+        },
+        _ => Message::Other,
+    }
+}
+
+/// The answer to the echo request `request`, as an IP packet
+pub(crate) fn echo_reply(cfg: &crate::Config, request: &ip::Packet) -> Vec<u8> {
+    // the same identifier, sequence number and data come back (RFC 792, RFC 4443 S4.2)
+    let mut reply = request.payload.to_vec();
+    reply[0] = match request.src {
+        IpAddr::V4(_) => ECHO_REPLY,
+        IpAddr::V6(_) => ECHO_REPLY6,
+    };
+    packet(cfg, request.src, reply)
+}
+
+/// A port unreachable message about `original`, a datagram nobody takes, as an IP packet
+pub(crate) fn port_unreachable(cfg: &crate::Config, original: &ip::Packet) -> Vec<u8> {
+    let (mut message, max) = match original.src {
+        IpAddr::V4(_) => (vec![DESTINATION_UNREACHABLE, PORT_UNREACHABLE, 0, 0, 0, 0, 0, 0], MAX_ERROR_LEN),
+        IpAddr::V6(_) => (vec![DESTINATION_UNREACHABLE6, PORT_UNREACHABLE6, 0, 0, 0, 0, 0, 0], MAX_ERROR_LEN6),
+    };
+    let room = std::cmp::min(max, cfg.mtu) - ip::header_len(original.src) - message.len();
+    let quoted = original.raw;
+    message.extend_from_slice(&quoted[..std::cmp::min(quoted.len(), room)]);
+    packet(cfg, original.src, message)
+}
+
+/// Put the checksum into `message` and send it to `dst`, as an IP packet
+fn packet(cfg: &crate::Config, dst: IpAddr, mut message: Vec<u8>) -> Vec<u8> {
+    let src = cfg.source(dst).expect("we only answer packets to our addresses");
+    let protocol = match dst {
+        IpAddr::V4(_) => PROTOCOL,
+        IpAddr::V6(_) => PROTOCOL6,
+    };
+    let ip = ip::Header::new(cfg.opts.ttl, protocol, src, dst);
+
+    message[2..4].copy_from_slice(&[0, 0]);
+    let mut sum = match ip {
+        // ICMPv6 covers the pseudo header too
+        ip::Header::V6(_) => ip.pseudo_header(message.len()),
+        ip::Header::V4(_) => Checksum::default(),
+    };
+    sum.add(&message);
+    message[2..4].copy_from_slice(&sum.finish().to_be_bytes());
+    ip.packet(&message)
+}
@@ -0,0 +1,352 @@
+//! A link that misbehaves on purpose: loss, delay, reordering, duplication, corruption and rate
+//! limits, modelled after Linux's netem.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::device::{Device, Waker};
+
+/// How the delay of a packet varies around `Impairments::delay`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distribution {
+    /// anywhere within `delay ± jitter`
+    Uniform,
+    /// normally distributed around `delay`, with `jitter` as the standard deviation
+    Normal,
+}
+
+/// What to do to the packets going through a link.
+///
+/// Probabilities are between 0 and 1. Parsing accepts netem's syntax, such as
+/// `delay 20ms 5ms distribution normal loss 1% reorder 10% rate 1mbit`, plus `seed N`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impairments {
+    /// probability that a packet is dropped
+    pub loss: f64,
+    /// delay of every packet
+    pub delay: Duration,
+    /// how far the delay may vary
+    pub jitter: Duration,
+    pub distribution: Distribution,
+    /// probability that a packet is sent without the delay, overtaking the ones before it
+    pub reorder: f64,
+    /// probability that a packet is sent twice
+    pub duplicate: f64,
+    /// probability that a bit of the packet is flipped
+    pub corrupt: f64,
+    /// bits per second the link carries, or `None` for no limit
+    pub rate: Option<u64>,
+    /// packets queued on the link before more are dropped
+    pub limit: usize,
+    /// seed of the random decisions, or `None` to pick one from the clock
+    pub seed: Option<u64>,
+}
+
+impl Default for Impairments {
+    fn default() -> Self {
+        Impairments {
+            loss: 0.0,
+            delay: Duration::default(),
+            jitter: Duration::default(),
+            distribution: Distribution::Uniform,
+            reorder: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            rate: None,
+            limit: 1000,
+            seed: None,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+fn parse_percent(s: Option<&str>) -> io::Result<f64> {
+    let s = s.ok_or_else(|| invalid("missing percentage"))?;
+    let p: f64 = s.trim_end_matches('%').parse().map_err(|_| invalid("bad percentage"))?;
+    if !(0.0..=100.0).contains(&p) {
+        return Err(invalid("percentage must be between 0 and 100"));
+    }
+    Ok(p / 100.0)
+}
+
+fn parse_time(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+    let (n, unit) = s.split_at(split);
+    let n: f64 = n.parse().ok()?;
+    let secs = match unit {
+        "us" => n / 1e6,
+        "ms" => n / 1e3,
+        "s" => n,
+        _ => return None,
+    };
+    Some(Duration::from_secs_f64(secs))
+}
+
+fn parse_rate(s: Option<&str>) -> io::Result<u64> {
+    let s = s.ok_or_else(|| invalid("missing rate"))?;
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| invalid("bad rate"))?;
+    let scale = match unit {
+        "" | "bit" => 1,
+        "kbit" => 1_000,
+        "mbit" => 1_000_000,
+        "gbit" => 1_000_000_000,
+        _ => return Err(invalid("rate unit must be bit, kbit, mbit or gbit")),
+    };
+    if n == 0 {
+        return Err(invalid("rate must not be zero"));
+    }
+    Ok(n * scale)
+}
+
+impl FromStr for Impairments {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let mut imp = Impairments::default();
+        let mut words = s.split_whitespace().peekable();
+        while let Some(word) = words.next() {
+            match word {
+                "loss" => imp.loss = parse_percent(words.next())?,
+                "duplicate" => imp.duplicate = parse_percent(words.next())?,
+                "corrupt" => imp.corrupt = parse_percent(words.next())?,
+                "reorder" => imp.reorder = parse_percent(words.next())?,
+                "delay" => {
+                    imp.delay = words.next().and_then(parse_time).ok_or_else(|| invalid("bad delay"))?;
+                    if let Some(jitter) = words.peek().and_then(|w| parse_time(w)) {
+                        imp.jitter = jitter;
+                        words.next();
+                    }
+                }
+                "distribution" => {
+                    imp.distribution = match words.next() {
+                        Some("uniform") => Distribution::Uniform,
+                        Some("normal") => Distribution::Normal,
+                        _ => return Err(invalid("distribution must be uniform or normal")),
+                    }
+                }
+                "rate" => imp.rate = Some(parse_rate(words.next())?),
+                "limit" => imp.limit = words.next().and_then(|w| w.parse().ok()).ok_or_else(|| invalid("bad limit"))?,
+                "seed" => imp.seed = Some(words.next().and_then(|w| w.parse().ok()).ok_or_else(|| invalid("bad seed"))?),
+                _ => return Err(invalid(&format!("unknown impairment '{}'", word))),
+            }
+        }
+        Ok(imp)
+    }
+}
+
+/// SplitMix64, which is plenty to roll the dice for a link
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        // 53 random bits make a uniform float
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// true with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        // don't use up randomness when nothing can happen, so that unrelated settings don't
+        // change the outcome of the others
+        p > 0.0 && self.next_f64() < p
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// The queue of one direction of a link, which applies the impairments as packets enter it
+pub(crate) struct Shaper {
+    imp: Impairments,
+    rng: Rng,
+    /// packets by the time they leave, and then in the order they came in
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    nqueued: u64,
+    /// when the link is done sending what is queued, for the rate limit
+    busy_until: Option<Instant>,
+}
+
+impl Shaper {
+    pub(crate) fn new(imp: Impairments) -> Self {
+        let seed = imp.seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+        });
+        Shaper::with_seed(imp, seed)
+    }
+
+    pub(crate) fn with_seed(imp: Impairments, seed: u64) -> Self {
+        Shaper {
+            imp,
+            rng: Rng::new(seed),
+            queue: BinaryHeap::new(),
+            nqueued: 0,
+            busy_until: None,
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        let (delay, jitter) = (self.imp.delay.as_secs_f64(), self.imp.jitter.as_secs_f64());
+        if jitter == 0.0 {
+            return self.imp.delay;
+        }
+        let d = match self.imp.distribution {
+            Distribution::Uniform => delay + jitter * (2.0 * self.rng.next_f64() - 1.0),
+            Distribution::Normal => {
+                // Box-Muller
+                let (u1, u2) = (1.0 - self.rng.next_f64(), self.rng.next_f64());
+                delay + jitter * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+            }
+        };
+        Duration::from_secs_f64(d.max(0.0))
+    }
+
+    /// Put a packet on the link at `now`. It may be dropped, duplicated or mangled on the way.
+    pub(crate) fn push(&mut self, now: Instant, packet: Vec<u8>) {
+        if self.queue.len() >= self.imp.limit || self.rng.chance(self.imp.loss) {
+            return;
+        }
+
+        let copies = if self.rng.chance(self.imp.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut packet = packet.clone();
+            if !packet.is_empty() && self.rng.chance(self.imp.corrupt) {
+                let bit = self.rng.below(packet.len() as u64 * 8);
+                packet[bit as usize / 8] ^= 1 << (bit % 8);
+            }
+
+            let mut at = now;
+            if let Some(rate) = self.imp.rate {
+                // wait for the packets ahead of us to be sent, then take our turn
+                let start = std::cmp::max(now, self.busy_until.unwrap_or(now));
+                let nanos = packet.len() as u128 * 8 * 1_000_000_000 / rate as u128;
+                at = start + Duration::from_nanos(nanos as u64);
+                self.busy_until = Some(at);
+            }
+            if !self.rng.chance(self.imp.reorder) {
+                at += self.delay();
+            }
+
+            self.queue.push(Reverse((at, self.nqueued, packet)));
+            self.nqueued += 1;
+        }
+    }
+
+    /// When the next packet leaves the link
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((at, _, _))| *at)
+    }
+
+    /// Take the next packet that has left the link by `now`.
+    pub(crate) fn pop(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.next_due()? > now {
+            return None;
+        }
+        self.queue.pop().map(|Reverse((_, _, packet))| packet)
+    }
+}
+
+/// A device whose packets go through `Impairments` on their way out and in.
+pub struct Impaired<D> {
+    inner: D,
+    egress: Shaper,
+    ingress: Shaper,
+}
+
+impl<D: Device> Impaired<D> {
+    pub fn new(inner: D, egress: Impairments, ingress: Impairments) -> Self {
+        Impaired {
+            inner,
+            egress: Shaper::new(egress),
+            ingress: Shaper::new(ingress),
+        }
+    }
+
+    /// Send what is due on the way out.
+    fn flush(&mut self, now: Instant) -> io::Result<()> {
+        while let Some(packet) = self.egress.pop(now) {
+            self.inner.send(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: Device> Device for Impaired<D> {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        loop {
+            let now = Instant::now();
+            // delayed packets go out as the stack comes by to wait for new ones
+            self.flush(now)?;
+            if let Some(packet) = self.ingress.pop(now) {
+                let n = std::cmp::min(packet.len(), buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                return Ok(Some(n));
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            let wake = [Some(deadline), self.egress.next_due(), self.ingress.next_due()]
+                .iter()
+                .flatten()
+                .min()
+                .copied()
+                .unwrap_or(deadline);
+            match self.inner.recv(buf, wake)? {
+                Some(n) => self.ingress.push(Instant::now(), buf[..n].to_vec()),
+                // cut short, maybe by our waker: let the stack look
+                None if Instant::now() < wake => return Ok(None),
+                None => {}
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        self.egress.push(now, buf.to_vec());
+        self.flush(now)?;
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn verifies_checksums(&self) -> bool {
+        // not once we mangled the packets ourselves
+        self.inner.verifies_checksums() && self.ingress.imp.corrupt == 0.0
+    }
+
+    fn fills_checksums(&self) -> bool {
+        // corruption happens on the wire, after the checksum was computed
+        self.inner.fills_checksums() && self.egress.imp.corrupt == 0.0
+    }
+
+    fn unknown_protocol(&self) -> u64 {
+        self.inner.unknown_protocol()
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+}
@@ -0,0 +1,163 @@
+//! What TCP, UDP and ICMP need from IPv4 and IPv6 alike: the header of the packets we send,
+//! and the addresses and payload of those we receive.
+
+use std::io;
+use std::net::IpAddr;
+
+use crate::checksum::Checksum;
+
+// IPv6 extension headers we skip to get to the payload (RFC 8200 S4)
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION_OPTIONS: u8 = 60;
+
+/// Bytes of IP header in front of the payload of the packets we send to `dst`
+pub(crate) fn header_len(dst: IpAddr) -> usize {
+    match dst {
+        IpAddr::V4(_) => 20,
+        IpAddr::V6(_) => 40,
+    }
+}
+
+/// The IP header of the packets we send from one address to another
+#[derive(Clone)]
+pub(crate) enum Header {
+    V4(etherparse::Ipv4Header),
+    V6(etherparse::Ipv6Header),
+}
+
+impl Header {
+    /// A header for packets that carry `protocol` from `src` to `dst`, which have to be of the
+    /// same family.
+    pub(crate) fn new(ttl: u8, protocol: u8, src: IpAddr, dst: IpAddr) -> Self {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut ip = etherparse::Ipv4Header::new(0, ttl, etherparse::IpTrafficClass::Tcp, src.octets(), dst.octets());
+                ip.protocol = protocol;
+                Header::V4(ip)
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => Header::V6(etherparse::Ipv6Header {
+                traffic_class: 0,
+                flow_label: 0,
+                payload_length: 0,
+                next_header: protocol,
+                hop_limit: ttl,
+                source: src.octets(),
+                destination: dst.octets(),
+            }),
+            _ => unreachable!("addresses of different families"),
+        }
+    }
+
+    pub(crate) fn header_len(&self) -> usize {
+        match self {
+            Header::V4(ip) => ip.header_len(),
+            Header::V6(_) => 40,
+        }
+    }
+
+    pub(crate) fn set_payload_len(&mut self, len: usize) {
+        match self {
+            Header::V4(ip) => ip.set_payload_len(len),
+            Header::V6(ip) => ip.set_payload_length(len),
+        }
+        .expect("Could not set payload len");
+    }
+
+    /// Ask routers to drop our packets rather than fragment them (RFC 1191). IPv6 routers
+    /// never fragment anyway.
+    pub(crate) fn dont_fragment(&mut self) {
+        if let Header::V4(ip) = self {
+            ip.dont_fragment = true;
+        }
+    }
+
+    pub(crate) fn write<W: io::Write>(&self, out: &mut W) {
+        match self {
+            Header::V4(ip) => ip.write(out),
+            Header::V6(ip) => ip.write(out),
+        }
+        .expect("Can't write ip header");
+    }
+
+    /// The pseudo header a transport checksum over `len` bytes of payload starts with
+    pub(crate) fn pseudo_header(&self, len: usize) -> Checksum {
+        match self {
+            Header::V4(ip) => Checksum::pseudo_header(ip.source.into(), ip.destination.into(), ip.protocol, len),
+            Header::V6(ip) => Checksum::pseudo_header(ip.source.into(), ip.destination.into(), ip.next_header, len),
+        }
+    }
+
+    /// A whole packet with `payload` behind this header
+    pub(crate) fn packet(mut self, payload: &[u8]) -> Vec<u8> {
+        self.set_payload_len(payload.len());
+        let mut packet = Vec::with_capacity(self.header_len() + payload.len());
+        self.write(&mut packet);
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+/// An IP packet, read down to the protocol it carries
+pub(crate) struct Packet<'a> {
+    pub(crate) src: IpAddr,
+    pub(crate) dst: IpAddr,
+    /// what the payload is, behind any IPv6 extension headers
+    pub(crate) protocol: u8,
+    /// the whole packet, headers included
+    pub(crate) raw: &'a [u8],
+    pub(crate) payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Read the headers of `raw`, which may be cut short behind them like the packets that ICMP
+    /// errors quote. `None` if they don't parse, or for IPv6 fragments, which we don't
+    /// reassemble.
+    pub(crate) fn parse(raw: &'a [u8]) -> Option<Self> {
+        match raw.first()? >> 4 {
+            4 => {
+                let iph = etherparse::Ipv4HeaderSlice::from_slice(raw).ok()?;
+                Some(Packet {
+                    src: iph.source_addr().into(),
+                    dst: iph.destination_addr().into(),
+                    protocol: iph.protocol(),
+                    raw,
+                    payload: &raw[iph.slice().len()..],
+                })
+            }
+            6 => {
+                let iph = etherparse::Ipv6HeaderSlice::from_slice(raw).ok()?;
+                let (protocol, payload) = skip_extensions(iph.next_header(), &raw[iph.slice().len()..])?;
+                Some(Packet {
+                    src: iph.source_addr().into(),
+                    dst: iph.destination_addr().into(),
+                    protocol,
+                    raw,
+                    payload,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Skip the extension headers between the IPv6 header and the payload, starting with one of
+/// type `next`. Returns what the payload is and where it starts.
+fn skip_extensions(mut next: u8, mut rest: &[u8]) -> Option<(u8, &[u8])> {
+    loop {
+        let len = match next {
+            HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS => (*rest.get(1)? as usize + 1) * 8,
+            // counted in 4 byte units, less 2 (RFC 4302 S2.2)
+            AUTHENTICATION => (*rest.get(1)? as usize + 2) * 4,
+            FRAGMENT => return None,
+            _ => return Some((next, rest)),
+        };
+        if rest.len() < len {
+            return None;
+        }
+        next = rest[0];
+        rest = &rest[len..];
+    }
+}
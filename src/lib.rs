@@ -1,156 +1,1204 @@
 use std::io;
 use std::io::prelude::*;
-use std::sync::{Arc, Mutex, Condvar};
-use std::collections::{HashMap, VecDeque, hash_map::Entry};
-use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::task::Waker;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque, hash_map};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::thread;
+use std::time::{Duration, Instant};
 
 mod tcp;
+mod checksum;
+mod ethernet;
+mod event_fd;
+mod icmp;
+mod ip;
+mod pmtu;
+mod poll;
+mod reassembly;
+mod udp;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod device;
+pub mod impair;
+pub mod sim;
 
-const SENDQUEUE_SIZE: usize = 1024;
+use device::Device;
+pub use poll::{Event, Poller, Ready};
+pub use udp::UdpSocket;
 
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
 struct Quad {
-    src: (Ipv4Addr, u16),
-    dst: (Ipv4Addr, u16),
+    src: (IpAddr, u16),
+    dst: (IpAddr, u16),
 }
 
-#[derive(Default)]
 struct C {
+    config: Config,
+    clock: Clock,
+    stats: Mutex<Stats>,
+    /// packets each worker's queue dropped for their protocol, as it last told us
+    link_unknown: Box<[AtomicU64]>,
+    /// packets of each worker's connections that came in on the queue of another one, which wakes
+    /// it up to take them
+    inboxes: Box<[Mutex<VecDeque<Vec<u8>>>]>,
+    /// connections each worker should look at before its next tick, because their user queued
+    /// something up for it
+    due: Box<[Mutex<BTreeSet<Quad>>]>,
+    /// how to cut each worker's wait for its queue short, if its device has a way
+    wakers: Box<[Option<device::Waker>]>,
+    fragments: Mutex<reassembly::Reassembler>,
     manager: Mutex<ConnectionManager>,
-    pending_var: Condvar,
-    rcv_var: Condvar
+    /// whether the manager has chores for the tick: connections to reset, path MTUs to expire or
+    /// a shutdown. Only changed with the manager locked.
+    manager_busy: AtomicBool,
+    /// the connections, split up by quad
+    shards: [Mutex<Shard>; SHARDS],
+    udp: Mutex<udp::Sockets>,
+    udp_var: Condvar,
+}
+
+impl C {
+    fn new(config: Config, clock: Clock, wakers: Vec<Option<device::Waker>>) -> InterfaceHandle {
+        Arc::new(C {
+            link_unknown: (0..config.workers).map(|_| AtomicU64::new(0)).collect(),
+            inboxes: (0..config.workers).map(|_| Mutex::default()).collect(),
+            due: (0..config.workers).map(|_| Mutex::default()).collect(),
+            wakers: wakers.into(),
+            config,
+            clock,
+            stats: Mutex::default(),
+            fragments: Mutex::default(),
+            manager: Mutex::default(),
+            manager_busy: AtomicBool::new(false),
+            shards: Default::default(),
+            udp: Mutex::default(),
+            udp_var: Condvar::new(),
+        })
+    }
+
+    /// The piece of the connection table `quad` is in
+    fn shard(&self, quad: &Quad) -> MutexGuard<'_, Shard> {
+        self.shards[quad.shard()].lock().unwrap()
+    }
+
+    /// Whether `worker` processes the connections of the shard at `index`
+    fn owns(&self, worker: usize, index: usize) -> bool {
+        index % self.config.workers == worker
+    }
+
+    /// The worker that processes the connection `quad`
+    fn owner(&self, quad: &Quad) -> usize {
+        quad.shard() % self.config.workers
+    }
+
+    /// Have the worker of `quad` look at it before its next tick, to send what the user queued
+    /// up or to tell the peer about room the user made.
+    fn poke(&self, quad: Quad) {
+        let worker = self.owner(&quad);
+        if self.due[worker].lock().unwrap().insert(quad) {
+            if let Some(waker) = &self.wakers[worker] {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Give `packet`, which came in on another queue, to `worker`.
+    fn hand_over(&self, worker: usize, packet: Vec<u8>) {
+        self.inboxes[worker].lock().unwrap().push_back(packet);
+        if let Some(waker) = &self.wakers[worker] {
+            waker.wake();
+        }
+    }
+
+    /// The worker that processes the connection `packet` belongs to, if it is a TCP segment that
+    /// came in one piece. Anything else may be handled by whichever worker received it.
+    fn worker_of(&self, packet: &[u8]) -> Option<usize> {
+        if self.config.workers == 1 {
+            return None;
+        }
+        let (src, dst, header_len): (IpAddr, IpAddr, usize) = match packet.first().map(|b| b >> 4) {
+            Some(4) => {
+                let iph = etherparse::Ipv4HeaderSlice::from_slice(packet).ok()?;
+                if iph.protocol() != tcp::PROTOCOL || iph.more_fragments() || iph.fragments_offset() != 0 {
+                    return None;
+                }
+                (iph.source_addr().into(), iph.destination_addr().into(), iph.slice().len())
+            }
+            Some(6) => {
+                // a segment behind extension headers goes wherever it came in, which is fine
+                let iph = etherparse::Ipv6HeaderSlice::from_slice(packet).ok()?;
+                if iph.next_header() != tcp::PROTOCOL {
+                    return None;
+                }
+                (iph.source_addr().into(), iph.destination_addr().into(), iph.slice().len())
+            }
+            _ => return None,
+        };
+        let ports = packet.get(header_len..header_len + 4)?;
+        let quad = Quad {
+            src: (src, u16::from_be_bytes([ports[0], ports[1]])),
+            dst: (dst, u16::from_be_bytes([ports[2], ports[3]])),
+        };
+        Some(self.owner(&quad))
+    }
+
+    /// Whether sockets may wait for the packet loop. A simulation only moves on between calls
+    /// into the stack, so there waiting would never end.
+    fn can_block(&self) -> bool {
+        matches!(self.clock, Clock::System)
+    }
+}
+
+/// Where the stack takes the time from
+enum Clock {
+    System,
+    Virtual(Arc<sim::VirtualClock>),
+}
+
+impl Clock {
+    fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Virtual(clock) => clock.now(),
+        }
+    }
+}
+
+/// Settings fixed when the `Interface` is built
+pub(crate) struct Config {
+    /// our address and the prefix length of the subnet it is in
+    pub(crate) local: (Ipv4Addr, u8),
+    /// the same for IPv6, if we speak it
+    pub(crate) local6: Option<(Ipv6Addr, u8)>,
+    pub(crate) mtu: usize,
+    pub(crate) opts: SocketOptions,
+    /// whether incoming checksums are left for us to check
+    verify_checksums: bool,
+    /// how many threads process packets, each on its own queue of the device
+    workers: usize,
+    /// secret mixed into initial sequence numbers, and when their clock started (RFC 6528)
+    pub(crate) isn_key: u64,
+    pub(crate) isn_epoch: Instant,
+}
+
+impl Config {
+    /// largest TCP payload that fits in one IP packet to `dst` on this link
+    pub(crate) fn mss(&self, dst: IpAddr) -> u16 {
+        // the IP header and 20 bytes of TCP header without options
+        (self.mtu - ip::header_len(dst) - 20) as u16
+    }
+
+    /// Our address to send packets to `dst` from, if we have one of its family
+    pub(crate) fn source(&self, dst: IpAddr) -> Option<IpAddr> {
+        match dst {
+            IpAddr::V4(_) => Some(self.local.0.into()),
+            IpAddr::V6(_) => self.local6.map(|(addr, _)| addr.into()),
+        }
+    }
+
+    /// Whether `addr` is one of our addresses
+    pub(crate) fn owns(&self, addr: IpAddr) -> bool {
+        self.source(addr) == Some(addr)
+    }
+
+    /// Whether a packet from `src` to `dst` is meant for us and could have come from a real host
+    fn accepts(&self, src: IpAddr, dst: IpAddr) -> bool {
+        if !self.owns(dst) || src == dst || src.is_unspecified() || src.is_multicast() {
+            return false;
+        }
+        match src {
+            IpAddr::V4(src) => self.accepts4(src),
+            // no broadcast in IPv6, but loopback packets have no business on a link (RFC 4291 S2.5.3)
+            IpAddr::V6(src) => !src.is_loopback(),
+        }
+    }
+
+    /// Whether `src` could be a real IPv4 host, rather than the address of a whole subnet
+    fn accepts4(&self, src: Ipv4Addr) -> bool {
+        let (addr, prefix) = self.local;
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        let network = u32::from(addr) & mask;
+        let broadcast = network | !mask;
+        let src_bits = u32::from(src);
+        if prefix < 31 && (src_bits == network || src_bits == broadcast) {
+            return false;
+        }
+
+        !src.is_broadcast()
+    }
 }
 
 type InterfaceHandle = Arc<C>;
 
+/// Counters of incoming packets an `Interface` dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// packets whose IPv4 header checksum was wrong
+    pub bad_ip_checksum: u64,
+    /// segments whose TCP checksum was wrong
+    pub bad_tcp_checksum: u64,
+    /// ICMP messages whose checksum was wrong
+    pub bad_icmp_checksum: u64,
+    /// UDP datagrams whose checksum was wrong
+    pub bad_udp_checksum: u64,
+    /// UDP datagrams that came in while their socket had too many queued up
+    pub udp_dropped: u64,
+    /// fragmented datagrams given up on: incomplete for too long, inconsistent, or evicted to
+    /// stay within the memory limit
+    pub reassembly_failures: u64,
+    /// packets that carried neither IPv4 nor IPv6
+    pub unknown_protocol: u64,
+}
+
 pub struct Interface {
     ih: Option<InterfaceHandle>,
-    jh: Option<thread::JoinHandle<io::Result<()>>>,
+    /// the packet threads, none in a simulation
+    jh: Vec<thread::JoinHandle<io::Result<()>>>,
 }
 
+/// Options applied to every connection of an `Interface`.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// time to live of outgoing IP packets
+    pub ttl: u8,
+    /// bytes `TcpStream::write` buffers before it returns `WouldBlock`
+    pub send_buffer_size: usize,
+    /// bytes of received data buffered for `TcpStream::read`, which is the window we advertise
+    pub recv_buffer_size: usize,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            ttl: 64,
+            send_buffer_size: 1024,
+            recv_buffer_size: 1024,
+        }
+    }
+}
+
+/// Configures and creates an `Interface`.
+///
+/// By default it opens `tun0` as 192.168.0.2/24 with a 1500 byte MTU, which matches what
+/// `run.sh` sets up on the host side. The host end of the device still has to be given an
+/// address in the same subnet, and the same MTU, by whoever creates it. With `ethernet` it opens
+/// a tap device instead, and behaves like a host on the Ethernet segment the device is bridged to.
+#[derive(Debug, Clone)]
+pub struct InterfaceBuilder {
+    name: String,
+    local: (Ipv4Addr, u8),
+    local6: Option<(Ipv6Addr, u8)>,
+    /// our hardware address, if the link carries Ethernet frames
+    ethernet: Option<[u8; 6]>,
+    /// whether the packets of the link come with flags and protocol in front
+    packet_info: bool,
+    gateway: Option<Ipv4Addr>,
+    gateway6: Option<Ipv6Addr>,
+    mtu: Option<usize>,
+    /// how many queues of the tun device `build` opens
+    queues: usize,
+    opts: SocketOptions,
+    impair: Option<impair::Impairments>,
+}
+
+impl Default for InterfaceBuilder {
+    fn default() -> Self {
+        InterfaceBuilder {
+            name: String::from("tun0"),
+            local: (Ipv4Addr::new(192, 168, 0, 2), 24),
+            local6: None,
+            ethernet: None,
+            packet_info: false,
+            gateway: None,
+            gateway6: None,
+            mtu: None,
+            queues: 1,
+            opts: SocketOptions::default(),
+            impair: None,
+        }
+    }
+}
+
+impl InterfaceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name of the tun or tap device `build` opens.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Our address and the prefix length of its subnet. Packets to any other address are dropped.
+    pub fn address(mut self, addr: Ipv4Addr, prefix: u8) -> Self {
+        self.local = (addr, prefix);
+        self
+    }
+
+    /// Our IPv6 address and the prefix length of its subnet. Without one the interface speaks
+    /// IPv4 only.
+    pub fn address6(mut self, addr: Ipv6Addr, prefix: u8) -> Self {
+        self.local6 = Some((addr, prefix));
+        self
+    }
+
+    /// Run on a link of Ethernet frames, like a tap device, as the host with the hardware address
+    /// `mac`. The interface resolves the addresses of its neighbors with ARP and neighbor
+    /// discovery, and answers for its own.
+    pub fn ethernet(mut self, mac: [u8; 6]) -> Self {
+        self.ethernet = Some(mac);
+        self
+    }
+
+    /// The router that packets to addresses outside our subnet go to, one for each family. Only
+    /// an Ethernet link needs one: everything sent to a tun device goes to the host.
+    pub fn gateway(mut self, addr: impl Into<IpAddr>) -> Self {
+        match addr.into() {
+            IpAddr::V4(addr) => self.gateway = Some(addr),
+            IpAddr::V6(addr) => self.gateway6 = Some(addr),
+        }
+        self
+    }
+
+    /// Open the tun device with packet information: every packet comes with flags and its
+    /// protocol in front, and packets of protocols other than IPv4 and IPv6 are dropped.
+    pub fn packet_info(mut self, on: bool) -> Self {
+        self.packet_info = on;
+        self
+    }
+
+    /// Largest IP packet sent or received on the link. The MSS is derived from it.
+    ///
+    /// Defaults to 1500 for the tun device, and to `Device::mtu` otherwise.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
 
+    /// Open the tun device with `queues` queues, each with a thread of its own processing the
+    /// packets of the connections assigned to it. Up to 16.
+    ///
+    /// The kernel hands the packets of a flow to the queue that last sent one of it, so after
+    /// their first packet the flows stay with their thread. Those that still come in on another
+    /// queue are handed over to their thread.
+    pub fn queues(mut self, queues: usize) -> Self {
+        self.queues = queues;
+        self
+    }
+
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.opts.send_buffer_size = size;
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.opts.recv_buffer_size = size;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.opts.ttl = ttl;
+        self
+    }
+
+    /// Replace all the options new connections start with.
+    pub fn socket_options(mut self, opts: SocketOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Put the packets we send through `impairments`, like netem on the device would.
+    pub fn impair(mut self, impairments: impair::Impairments) -> Self {
+        self.impair = Some(impairments);
+        self
+    }
+
+    /// Open the tun or tap device and start the stack on it.
+    pub fn build(self) -> io::Result<Interface> {
+        let mtu = self.mtu.unwrap_or(1500);
+        match self.ethernet {
+            Some(_) if self.queues != 1 => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "multiple queues are only for tun devices"))
+            }
+            Some(_) => {
+                let nic = device::Tap::open(&self.name, mtu)?;
+                self.build_with(nic)
+            }
+            None if self.queues != 1 => {
+                let nics = device::Tun::open_queues(&self.name, mtu, self.queues, self.packet_info)?;
+                self.build_with_queues(nics)
+            }
+            None if self.packet_info => {
+                let nic = device::Tun::open_with_packet_info(&self.name, mtu)?;
+                self.build_with(nic)
+            }
+            None => {
+                let nic = device::Tun::open(&self.name, mtu)?;
+                self.build_with(nic)
+            }
+        }
+    }
+
+    /// Start the stack on any other link. With `ethernet`, the link carries Ethernet frames, and
+    /// with `packet_info` its packets come with flags and protocol in front.
+    pub fn build_with<D: Device>(self, nic: D) -> io::Result<Interface> {
+        self.build_with_queues(vec![nic])
+    }
+
+    /// Start the stack on the queues of a multi-queue link, with a thread for each, like
+    /// `queues` does for the tun device. `queues` itself is ignored.
+    pub fn build_with_queues<D: Device>(self, nics: Vec<D>) -> io::Result<Interface> {
+        match self.ethernet {
+            Some(mac) => {
+                let nics = nics.into_iter()
+                    .map(|nic| ethernet::Ethernet::new(nic, mac, self.local, self.local6, self.gateway, self.gateway6))
+                    .collect();
+                self.start(nics)
+            }
+            None if self.packet_info => self.start(nics.into_iter().map(device::PacketInfo::new).collect()),
+            None => self.start(nics),
+        }
+    }
+
+    fn start<D: Device>(mut self, nics: Vec<D>) -> io::Result<Interface> {
+        let impair = self.impair.take();
+        let config = match nics.first() {
+            Some(nic) => self.config(nic, nics.len())?,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no queues to run on")),
+        };
+        match impair {
+            Some(egress) => {
+                let nics = nics.into_iter()
+                    .map(|nic| impair::Impaired::new(nic, egress, impair::Impairments::default()))
+                    .collect();
+                Interface::start(nics, config)
+            }
+            None => Interface::start(nics, config),
+        }
+    }
+
+    fn config<D: Device>(self, nic: &D, workers: usize) -> io::Result<Config> {
+        let mtu = self.mtu.unwrap_or_else(|| nic.mtu());
+        if self.local.1 > 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "prefix length is longer than 32 bits"));
+        }
+        // every IPv4 host must handle 68 byte packets (RFC 791), and we need room for our headers
+        if mtu < 68 || mtu > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "MTU must be between 68 and 65535"));
+        }
+        if let Some((_, prefix)) = self.local6 {
+            if prefix > 128 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "prefix length is longer than 128 bits"));
+            }
+            // every IPv6 link carries 1280 byte packets, and we don't fragment (RFC 8200 S5)
+            if mtu < 1280 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "IPv6 needs an MTU of at least 1280"));
+            }
+        }
+        match self.ethernet {
+            Some(mac) if mac[0] & 1 != 0 => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "hardware address is a multicast address"));
+            }
+            Some(_) if self.packet_info => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "packet information is only for tun devices"));
+            }
+            Some(_) if workers > 1 => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "multiple queues are only for tun devices"));
+            }
+            None if self.gateway.is_some() || self.gateway6.is_some() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "gateways need an Ethernet link"));
+            }
+            _ => {}
+        }
+        if mtu > nic.mtu() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "MTU is larger than the device supports"));
+        }
+        if self.opts.send_buffer_size == 0 || self.opts.recv_buffer_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer sizes must not be zero"));
+        }
+        // a worker owns whole shards, so more of them would have nothing to do
+        if workers > SHARDS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "at most 16 queues are supported"));
+        }
+
+        Ok(Config {
+            local: self.local,
+            local6: self.local6,
+            mtu,
+            opts: self.opts,
+            verify_checksums: !nic.verifies_checksums(),
+            workers,
+            isn_key: {
+                use std::hash::{BuildHasher, Hasher};
+                std::collections::hash_map::RandomState::new().build_hasher().finish()
+            },
+            isn_epoch: Instant::now(),
+        })
+    }
+}
+
+/// How `Interface::shutdown` tears down the connections that are still open.
+#[derive(Debug, Clone, Copy)]
+pub enum Teardown {
+    /// Send a FIN on every connection and wait up to the given time for the closes to finish.
+    /// Connections that are still open after that are reset.
+    Graceful(Duration),
+    /// Reset every connection right away.
+    Abort,
+}
+
+/// What is shared by all connections: listeners, teardown and what we know about paths. Taken
+/// before the lock of any shard.
 #[derive(Default)]
 struct ConnectionManager {
-    terminate: bool,
-    connections: HashMap<Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>
+    /// set once the interface is shutting down: connections still open at this instant are reset
+    terminate: Option<Instant>,
+    pending: HashMap<u16, VecDeque<Quad>>,
+    /// connections torn down locally that still owe the peer a RST
+    aborted: Vec<tcp::Connection>,
+    /// path MTUs routers told us about
+    paths: pmtu::Paths,
+    /// where to start looking for a free ephemeral port
+    next_port: u16,
+    /// whoever waits on each listener
+    listeners: HashMap<u16, WaitList>,
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
-    loop {
-        // TODO: set timeout for recv for TCP timers or ConnectionManager::terminate
-        let nbytes = nic.recv(&mut buf[..])?;
-
-        // TODO: if self.terminate && arc.get_Strong_refs(ih) == 1; then tear down all connections and return
-
-
-        // if s/withoud_packet_info/new/:
-        //
-        // let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
-        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-        // if eth_proto != 0x0800 {
-        //     // no ipv4 packet
-        //     continue;
-        // }
-        // and also include on send
-
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
-                let src = iph.source_addr();
-                let destination = iph.destination_addr();
-                if iph.protocol() != 0x06 {
-                    // not tcp
-                    continue;
+#[cfg(feature = "test-hooks")]
+thread_local! {
+    /// the worker this thread runs, if it runs one
+    static WORKER: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+/// How many pieces the connection table is split into, so that streams in different pieces
+/// don't wait for each other
+const SHARDS: usize = 16;
+
+/// The connections whose quads hash to one piece of the connection table
+#[derive(Default)]
+struct Shard {
+    /// ordered, so that a simulation visits connections the same way every run
+    connections: BTreeMap<Quad, tcp::Connection>,
+    /// whoever waits on each stream
+    waiters: HashMap<Quad, WaitList>,
+}
+
+impl Quad {
+    /// Index of the shard the connection is in. Stable across runs, so simulations stay
+    /// reproducible.
+    fn shard(&self) -> usize {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        Hash::hash(self, &mut hasher);
+        (hasher.finish() % SHARDS as u64) as usize
+    }
+}
+
+/// Threads, tasks and event loops waiting on one stream or listener. A listener is readable
+/// while connections are pending.
+#[derive(Default)]
+struct WaitList {
+    /// threads blocked on the stream or listener, which wait on a clone so the list can go
+    /// away under them
+    threads: Arc<Condvar>,
+    read: Option<Waker>,
+    write: Option<Waker>,
+    /// eventfd, which is signaled on every change for as long as the stream or listener is
+    /// around
+    fd: Option<Arc<event_fd::EventFd>>,
+}
+
+impl WaitList {
+    /// Wake up the tasks and event loops waiting for what became `a`. The threads are returned
+    /// rather than notified, so they can be once the lock is released: woken up while it is
+    /// held they would only line up for it behind the packet loop.
+    fn wake(&mut self, a: tcp::Available) -> Option<Arc<Condvar>> {
+        if a.is_empty() {
+            return None;
+        }
+        if let Some(fd) = &self.fd {
+            fd.signal();
+        }
+        if a.contains(tcp::Available::READ) {
+            if let Some(w) = self.read.take() {
+                w.wake();
+            }
+        }
+        if a.intersects(tcp::Available::WRITE | tcp::Available::FLUSHED) {
+            if let Some(w) = self.write.take() {
+                w.wake();
+            }
+        }
+        Some(self.threads.clone())
+    }
+
+    /// A stream or listener would block, so its eventfd waits for the next change.
+    fn reset_fd(&self) {
+        if let Some(fd) = &self.fd {
+            fd.reset();
+        }
+    }
+}
+
+/// Wake up the threads handed out by `WaitList::wake`.
+fn notify(threads: impl IntoIterator<Item = Arc<Condvar>>) {
+    threads.into_iter().for_each(|var| var.notify_all());
+}
+
+impl Shard {
+    /// The wait list of `quad`, which is created once someone waits and lives as long as the
+    /// stream, past the connection that may go away first
+    fn wait_list(&mut self, quad: Quad) -> &mut WaitList {
+        self.waiters.entry(quad).or_default()
+    }
+
+    /// The connection `quad` became `a`.
+    fn wake(&mut self, quad: &Quad, a: tcp::Available) -> Option<Arc<Condvar>> {
+        self.waiters.get_mut(quad).and_then(|w| w.wake(a))
+    }
+
+    fn reset_fd(&self, quad: &Quad) {
+        if let Some(w) = self.waiters.get(quad) {
+            w.reset_fd();
+        }
+    }
+
+    fn wake_all(&mut self) -> Vec<Arc<Condvar>> {
+        self.waiters.values_mut().filter_map(|w| w.wake(tcp::Available::all())).collect()
+    }
+}
+
+/// Ports we pick local ports for outgoing connections from (RFC 6335 S6)
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+impl ConnectionManager {
+    /// The wait list of the listener on `port`, made like those of streams
+    fn listener(&mut self, port: u16) -> &mut WaitList {
+        self.listeners.entry(port).or_default()
+    }
+
+    fn wake_accept(&mut self, port: u16) -> Option<Arc<Condvar>> {
+        self.listeners.get_mut(&port).and_then(|w| w.wake(tcp::Available::READ))
+    }
+
+    fn wake_listeners(&mut self) -> Vec<Arc<Condvar>> {
+        self.listeners.values_mut().filter_map(|w| w.wake(tcp::Available::READ)).collect()
+    }
+
+    /// A local port for a connection from `local` to `remote`
+    fn ephemeral_port(&mut self, ih: &C, local: IpAddr, remote: (IpAddr, u16)) -> io::Result<u16> {
+        let nports = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start() + 1;
+        for _ in 0..nports {
+            let port = EPHEMERAL_PORTS.start() + self.next_port % nports;
+            self.next_port = self.next_port.wrapping_add(1);
+
+            let quad = Quad {
+                src: remote,
+                dst: (local, port),
+            };
+            let in_use = self.pending.contains_key(&port) || ih.shard(&quad).connections.contains_key(&quad);
+            if !in_use {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no free local port"))
+    }
+}
+
+/// How often the packet loop gets to its chores: timers, teardown, and what users didn't poke it
+/// about
+const TICK: Duration = Duration::from_millis(10);
+
+/// Do the chores that don't wait for a packet: timers, queued up data and teardown, of the
+/// connections `worker` owns.
+///
+/// Returns `false` once the interface has shut down.
+fn on_tick<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, worker: usize) -> io::Result<bool> {
+    ih.fragments.lock().unwrap().expire(now);
+
+    let outgoing = std::mem::take(&mut ih.udp.lock().unwrap().outgoing);
+    for datagram in outgoing {
+        nic.send(&datagram)?;
+    }
+
+    ih.link_unknown[worker].store(nic.unknown_protocol(), Ordering::Relaxed);
+
+    // most ticks have nothing to do with the manager, and then leave its lock alone
+    if ih.manager_busy.load(Ordering::Acquire) && !on_manager_tick(nic, ih, now, worker)? {
+        return Ok(false);
+    }
+
+    // this looks at all of them anyway
+    ih.due[worker].lock().unwrap().clear();
+    // one shard at a time, so streams of the others can go on meanwhile
+    for (_, shard) in ih.shards.iter().enumerate().filter(|(i, _)| ih.owns(worker, *i)) {
+        let mut guard = shard.lock().unwrap();
+        let shard = &mut *guard;
+        let mut threads = Vec::new();
+        for (quad, c) in shard.connections.iter_mut() {
+            let before = c.availability();
+            c.on_tick(nic, now)?;
+            // somebody may have timed out
+            if let Some(w) = shard.waiters.get_mut(quad) {
+                threads.extend(w.wake(c.availability() & !before));
+            }
+        }
+        // nobody is going to look at these anymore
+        shard.connections.retain(|_, c| !(c.released && c.is_closed()));
+        drop(guard);
+        notify(threads);
+    }
+    Ok(true)
+}
+
+/// Do what the users of `worker`'s connections that were poked queued up, without waiting for the
+/// next tick.
+fn on_due<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, worker: usize) -> io::Result<()> {
+    let due = std::mem::take(&mut *ih.due[worker].lock().unwrap());
+    for quad in due {
+        let mut guard = ih.shard(&quad);
+        let shard = &mut *guard;
+        if let Some(c) = shard.connections.get_mut(&quad) {
+            let before = c.availability();
+            c.on_tick(nic, now)?;
+            let after = c.availability();
+            let threads = shard.wake(&quad, after & !before);
+            drop(guard);
+            notify(threads);
+        }
+    }
+    Ok(())
+}
+
+/// The chores of `on_tick` that need the manager: resetting aborted connections, expiring path
+/// MTUs, and shutting down.
+///
+/// Returns `false` once the interface has shut down.
+fn on_manager_tick<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, worker: usize) -> io::Result<bool> {
+    let mut cm = ih.manager.lock().unwrap();
+    cm.paths.expire(now);
+    for mut c in cm.aborted.drain(..) {
+        c.send_rst(nic, now)?;
+    }
+
+    if let Some(deadline) = cm.terminate {
+        let mut shards: Vec<_> = ih.shards.iter().enumerate()
+            .map(|(i, s)| (ih.owns(worker, i), s.lock().unwrap()))
+            .collect();
+        for (_, shard) in shards.iter_mut().filter(|(mine, _)| *mine) {
+            // nobody cares about old duplicates anymore once we are gone
+            shard.connections.retain(|_, c| !c.is_closed() && !c.is_time_wait());
+        }
+        // every worker stays until all are done, to keep receiving on its queue
+        if shards.iter().all(|(_, s)| s.connections.is_empty()) || now >= deadline {
+            for (_, shard) in shards.iter_mut().filter(|(mine, _)| *mine) {
+                for (_, mut c) in std::mem::take(&mut shard.connections) {
+                    c.send_rst(nic, now)?;
                 }
-                
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
-                    Ok(tcph) => {
-                        
-                        let datai = iph.slice().len() + tcph.slice().len(); 
-                        // (srcip, srcport, dstip, dstport)
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let cm = &mut *cmg;
-
-                        let quad = Quad {
-                            src: (src, tcph.source_port()),
-                            dst: (destination, tcph.destination_port()),
-                        };
-
-                        match cm.connections.entry(quad) {
-                            Entry::Occupied(mut c) => {
-                                let a = c.get_mut().on_packet(&mut nic, iph, tcph, &buf[datai..nbytes])?;
-                                
-                                
-                                // TODO: compare before/after
-                                drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.rcv_var.notify_all();
-                                }
-                                if a.contains(tcp::Available::WRITE)  {
-                                    //TODO: ih.send_var.notify_all();
-                                }
-                            },
-                            Entry::Vacant(e) => {
-                                if let Some(pending) = cm.pending.get_mut(&tcph.destination_port()) {
-                                    if let Some(c) = tcp::Connection::accept(&mut nic, iph, tcph, &buf[datai..nbytes])? {
-                                        e.insert(c);
-                                        pending.push_back(quad);
-                                        drop(cmg);
-                                        ih.pending_var.notify_all();
-                                        // TODO: Wake up pending accept()
-                                    }
-                                }
-                                
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("Ignoring tcp packet {:?}", e);
+            }
+            return Ok(false);
+        }
+
+        for (_, shard) in shards.iter_mut().filter(|(mine, _)| *mine) {
+            for c in shard.connections.values_mut() {
+                c.close();
+            }
+        }
+    }
+
+    let busy = !cm.paths.is_empty() || cm.terminate.is_some();
+    ih.manager_busy.store(busy, Ordering::Release);
+    Ok(true)
+}
+
+/// Handle one IP packet that came in on `nic`.
+fn on_packet<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, packet: &[u8]) -> io::Result<()> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => on_ipv4(nic, ih, now, packet),
+        Some(6) => on_ipv6(nic, ih, now, packet),
+        _ => {
+            ih.stats.lock().unwrap().unknown_protocol += 1;
+            Ok(())
+        }
+    }
+}
+
+fn on_ipv4<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, packet: &[u8]) -> io::Result<()> {
+    match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+        Ok(iph) => {
+            if ih.config.verify_checksums && !checksum::ipv4_header_ok(iph.slice()) {
+                ih.stats.lock().unwrap().bad_ip_checksum += 1;
+                return Ok(());
+            }
+
+            if !ih.config.accepts(iph.source_addr().into(), iph.destination_addr().into()) {
+                // not for us
+                return Ok(());
+            }
+
+            // anything past the total length is padding of the link
+            let total = iph.total_len() as usize;
+            if total < iph.slice().len() || total > packet.len() {
+                // truncated
+                return Ok(());
+            }
+            let packet = &packet[..total];
+
+            if iph.more_fragments() || iph.fragments_offset() != 0 {
+                let datagram = ih.fragments.lock().unwrap().add(now, &iph, &packet[iph.slice().len()..]);
+                return match datagram {
+                    Some(datagram) => on_datagram(nic, ih, now, &datagram),
+                    None => Ok(()),
+                };
+            }
+            on_datagram(nic, ih, now, packet)
+        },
+        Err(e) => {
+            eprintln!("Ignoring ipv4 packet {:?}", e);
+            Ok(())
+        }
+    }
+}
+
+fn on_ipv6<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, packet: &[u8]) -> io::Result<()> {
+    match etherparse::Ipv6HeaderSlice::from_slice(packet) {
+        Ok(iph) => {
+            if !ih.config.accepts(iph.source_addr().into(), iph.destination_addr().into()) {
+                // not for us
+                return Ok(());
+            }
+
+            // there is no header checksum, and anything past the payload is padding of the link
+            let total = iph.slice().len() + iph.payload_length() as usize;
+            if total > packet.len() {
+                // truncated
+                return Ok(());
+            }
+            on_datagram(nic, ih, now, &packet[..total])
+        },
+        Err(e) => {
+            eprintln!("Ignoring ipv6 packet {:?}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Handle a whole IP datagram for us, with the padding cut off.
+fn on_datagram<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, packet: &[u8]) -> io::Result<()> {
+    let packet = match ip::Packet::parse(packet) {
+        Some(packet) => packet,
+        // broken extension headers, or a fragment of an IPv6 datagram
+        None => return Ok(()),
+    };
+    match packet.protocol {
+        tcp::PROTOCOL => on_tcp(nic, ih, now, &packet),
+        udp::PROTOCOL => on_udp(nic, ih, &packet),
+        icmp::PROTOCOL if packet.src.is_ipv4() => on_icmp(nic, ih, now, &packet),
+        icmp::PROTOCOL6 if packet.src.is_ipv6() => on_icmp(nic, ih, now, &packet),
+        // nothing else we speak
+        _ => Ok(()),
+    }
+}
+
+fn on_tcp<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, packet: &ip::Packet) -> io::Result<()> {
+    let segment = packet.payload;
+    if ih.config.verify_checksums && !checksum::transport_ok(packet.src, packet.dst, tcp::PROTOCOL, segment) {
+        ih.stats.lock().unwrap().bad_tcp_checksum += 1;
+        return Ok(());
+    }
+
+    match etherparse::TcpHeaderSlice::from_slice(segment) {
+        Ok(tcph) => {
+
+            let data = &segment[tcph.slice().len()..];
+            // (srcip, srcport, dstip, dstport)
+            let quad = Quad {
+                src: (packet.src, tcph.source_port()),
+                dst: (packet.dst, tcph.destination_port()),
+            };
+
+            let mut guard = ih.shard(&quad);
+            let shard = &mut *guard;
+            if let Some(c) = shard.connections.get_mut(&quad) {
+                let before = c.availability();
+                c.on_packet(nic, now, tcph, data)?;
+                // send what the segment made room for right away, rather than on the next tick
+                c.on_tick(nic, now)?;
+                let after = c.availability();
+                let threads = shard.wake(&quad, after & !before);
+                drop(guard);
+                notify(threads);
+                return Ok(());
+            }
+            // a new connection is up to the listeners, whose lock comes first
+            drop(guard);
+
+            let mut cmg = ih.manager.lock().unwrap();
+            let cm = &mut *cmg;
+            if ih.shard(&quad).connections.contains_key(&quad) {
+                // another worker got there first, with a segment that came in fragments
+                drop(cmg);
+                return on_tcp(nic, ih, now, packet);
+            }
+            if let Some(pending) = cm.pending.get_mut(&tcph.destination_port()) {
+                if let Some(mut c) = tcp::Connection::accept(nic, now, &ih.config, packet, tcph, data)? {
+                    if let Some(mtu) = cm.paths.get(packet.src) {
+                        c.on_path_mtu(now, mtu);
                     }
+                    // only with the manager lock are connections added for listeners, so the
+                    // quad is still free
+                    ih.shard(&quad).connections.insert(quad, c);
+                    pending.push_back(quad);
+                    let threads = cm.wake_accept(quad.dst.1);
+                    drop(cmg);
+                    notify(threads);
                 }
-            },
-            Err(e) => {
-                eprintln!("Ignoring ipv4 packet {:?}", e);
+            } else {
+                // nobody is listening on this port (anymore)
+                tcp::send_rst_reply(nic, &ih.config, packet, tcph, data)?;
+            }
+        },
+        Err(e) => {
+            eprintln!("Ignoring tcp packet {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+fn on_udp<D: Device>(nic: &mut D, ih: &InterfaceHandle, packet: &ip::Packet) -> io::Result<()> {
+    let segment = packet.payload;
+    if segment.len() < 8 {
+        return Ok(());
+    }
+    let len = u16::from_be_bytes([segment[4], segment[5]]) as usize;
+    if len < 8 || len > segment.len() {
+        return Ok(());
+    }
+    let segment = &segment[..len];
+    // a zero checksum means the sender didn't compute one, which only IPv4 allows (RFC 8200 S8.1)
+    let unchecked = packet.src.is_ipv4() && segment[6..8] == [0, 0];
+    if ih.config.verify_checksums && !unchecked && !checksum::transport_ok(packet.src, packet.dst, udp::PROTOCOL, segment) {
+        ih.stats.lock().unwrap().bad_udp_checksum += 1;
+        return Ok(());
+    }
+
+    let from = SocketAddr::new(packet.src, u16::from_be_bytes([segment[0], segment[1]]));
+    let port = u16::from_be_bytes([segment[2], segment[3]]);
+    let delivery = ih.udp.lock().unwrap().deliver(port, from, &segment[8..]);
+    match delivery {
+        udp::Delivery::Queued => ih.udp_var.notify_all(),
+        udp::Delivery::Dropped => ih.stats.lock().unwrap().udp_dropped += 1,
+        udp::Delivery::Unbound => {
+            nic.send(&icmp::port_unreachable(&ih.config, packet))?;
+        }
+    }
+    Ok(())
+}
+
+fn on_icmp<D: Device>(nic: &mut D, ih: &InterfaceHandle, now: Instant, packet: &ip::Packet) -> io::Result<()> {
+    let message = packet.payload;
+    let intact = match packet.src {
+        IpAddr::V4(_) => checksum::icmp_ok(message),
+        IpAddr::V6(_) => checksum::transport_ok(packet.src, packet.dst, icmp::PROTOCOL6, message),
+    };
+    if ih.config.verify_checksums && !intact {
+        ih.stats.lock().unwrap().bad_icmp_checksum += 1;
+        return Ok(());
+    }
+
+    match icmp::parse(packet) {
+        Some(icmp::Message::EchoRequest) => {
+            let reply = icmp::echo_reply(&ih.config, packet);
+            // we don't fragment, so pings that only fit reassembled go unanswered
+            if reply.len() <= ih.config.mtu {
+                nic.send(&reply)?;
+            }
+        }
+        Some(icmp::Message::TooBig { next_hop_mtu, original }) => on_fragmentation_needed(ih, now, next_hop_mtu, original),
+        Some(icmp::Message::Error { kind, hard, original }) => on_icmp_error(ih, kind, hard, original),
+        Some(icmp::Message::Other) | None => {}
+    }
+    Ok(())
+}
+
+/// The connection and sequence number of the segment an ICMP error quotes, if it is one of
+/// ours. Only the ports and the sequence number of the TCP header are sure to be there.
+fn quoted_segment(ih: &InterfaceHandle, original: &[u8]) -> Option<(Quad, u32)> {
+    let packet = ip::Packet::parse(original)?;
+    let tcph = packet.payload;
+    if !ih.config.owns(packet.src) || packet.protocol != tcp::PROTOCOL || tcph.len() < 8 {
+        return None;
+    }
+    let quad = Quad {
+        src: (packet.dst, u16::from_be_bytes([tcph[2], tcph[3]])),
+        dst: (packet.src, u16::from_be_bytes([tcph[0], tcph[1]])),
+    };
+    let seq = u32::from_be_bytes([tcph[4], tcph[5], tcph[6], tcph[7]]);
+    Some((quad, seq))
+}
+
+/// A router dropped `original`, one of our packets, because it was larger than `next_hop_mtu`.
+fn on_fragmentation_needed(ih: &InterfaceHandle, now: Instant, next_hop_mtu: u32, original: &[u8]) {
+    let (quad, seq) = match quoted_segment(ih, original) {
+        Some(quoted) => quoted,
+        None => return,
+    };
+    let mtu = match next_hop_mtu {
+        // only IPv4 routers leave it out, and they quote the total length of the packet
+        0 if quad.src.0.is_ipv4() => pmtu::plateau(u16::from_be_bytes([original[2], original[3]]) as usize),
+        mtu => mtu as usize,
+    };
+    let mtu = std::cmp::max(mtu, pmtu::min_mtu(quad.src.0));
+
+    let mut cm = ih.manager.lock().unwrap();
+    // believe only messages about segments we actually sent, anyone could make up the rest
+    if !ih.shard(&quad).connections.get(&quad).is_some_and(|c| c.in_flight(seq)) {
+        return;
+    }
+    cm.paths.lower(now, quad.src.0, mtu);
+    ih.manager_busy.store(true, Ordering::Release);
+    for shard in ih.shards.iter() {
+        let mut shard = shard.lock().unwrap();
+        for (_, c) in shard.connections.iter_mut().filter(|(q, _)| q.src.0 == quad.src.0) {
+            c.on_path_mtu(now, mtu);
+        }
+    }
+}
+
+/// `original`, one of our packets, could not be delivered.
+fn on_icmp_error(ih: &InterfaceHandle, kind: io::ErrorKind, hard: bool, original: &[u8]) {
+    let (quad, seq) = match quoted_segment(ih, original) {
+        Some(quoted) => quoted,
+        None => return,
+    };
+
+    let mut guard = ih.shard(&quad);
+    let shard = &mut *guard;
+    let c = match shard.connections.get_mut(&quad) {
+        // as above, only if it's about something we sent
+        Some(c) if c.in_flight(seq) => c,
+        _ => return,
+    };
+    let before = c.availability();
+    let after = c.on_icmp_error(kind, hard);
+    let threads = shard.wake(&quad, after & !before);
+    drop(guard);
+    notify(threads);
+}
+
+/// Whether we shut down or failed, nothing will make progress on the remaining connections
+/// anymore, so kick out everyone still waiting on them.
+fn finish(ih: &InterfaceHandle) {
+    let mut cm = ih.manager.lock().unwrap();
+    cm.terminate.get_or_insert_with(|| ih.clock.now());
+    cm.pending.clear();
+    cm.aborted.clear();
+    let mut threads = cm.wake_listeners();
+    for shard in ih.shards.iter() {
+        let mut shard = shard.lock().unwrap();
+        shard.connections.clear();
+        threads.extend(shard.wake_all());
+    }
+    drop(cm);
+    notify(threads);
+    ih.udp.lock().unwrap().closed = true;
+    ih.udp_var.notify_all();
+}
+
+/// Process the packets of `worker`'s queue `nic`, handing those of connections owned by other
+/// workers over to them.
+fn packet_loop<D: Device>(nic: &mut D, ih: &InterfaceHandle, worker: usize) -> io::Result<()> {
+    #[cfg(feature = "test-hooks")]
+    WORKER.with(|w| w.set(Some(worker)));
+    let mut buf = vec![0u8; ih.config.mtu];
+    let mut next_tick = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= next_tick {
+            if !on_tick(nic, ih, now, worker)? {
+                return Ok(());
             }
+            next_tick = now + TICK;
+        }
+
+        let handed_over = std::mem::take(&mut *ih.inboxes[worker].lock().unwrap());
+        for packet in handed_over {
+            on_packet(nic, ih, Instant::now(), &packet)?;
         }
+        on_due(nic, ih, Instant::now(), worker)?;
 
+        // wait for a packet, but no longer than until the tick, or until a user pokes us
+        if let Some(nbytes) = nic.recv(&mut buf[..], next_tick)? {
+            let packet = &buf[..nbytes];
+            match ih.worker_of(packet) {
+                Some(owner) if owner != worker => ih.hand_over(owner, packet.to_vec()),
+                _ => on_packet(nic, ih, Instant::now(), packet)?,
+            }
+        }
     }
 }
 
 
 impl Interface {
+    /// Open `tun0` with the default settings of `InterfaceBuilder`.
     pub fn new() -> io::Result<Self> {
-        let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
+        InterfaceBuilder::new().build()
+    }
 
-        let ih: InterfaceHandle = Arc::default();
+    fn start<D: Device>(nics: Vec<D>, config: Config) -> io::Result<Self> {
+        let ih = C::new(config, Clock::System, nics.iter().map(Device::waker).collect());
+        let running = Arc::new(AtomicUsize::new(nics.len()));
 
-        let jh =  {
+        let jh = nics.into_iter().enumerate().map(|(worker, nic)| {
             let ih = ih.clone();
+            let running = running.clone();
             thread::spawn(move || {
-            let nic = nic;
+            let mut nic = nic;
             let ih = ih;
-            let buf = [0u8; 1504];
 
             // do what main does
-            packet_loop(nic, ih)
+            let r = packet_loop(&mut nic, &ih, worker);
+            // the last one out, or one that failed, wakes up everyone still waiting
+            let last = running.fetch_sub(1, Ordering::AcqRel) == 1;
+            if last || r.is_err() {
+                finish(&ih);
+            }
+            r
             })
-        };
+        }).collect();
 
         Ok(Interface {
             ih: Some(ih),
-            jh: Some(jh)
+            jh,
         })
     }
 
+    /// What the interface dropped so far.
+    pub fn stats(&self) -> Stats {
+        let ih = self.ih.as_ref().unwrap();
+        let stats = *ih.stats.lock().unwrap();
+        Stats {
+            reassembly_failures: ih.fragments.lock().unwrap().failed,
+            unknown_protocol: stats.unknown_protocol
+                + ih.link_unknown.iter().map(|n| n.load(Ordering::Relaxed)).sum::<u64>(),
+            ..stats
+        }
+    }
+
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
         let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        if cm.terminate.is_some() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "interface is shutting down"));
+        }
+
         match cm.pending.entry(port) {
-            Entry::Vacant(v) => {
+            hash_map::Entry::Vacant(v) => {
                 v.insert(VecDeque::new());
             },
-            Entry::Occupied(_) => {
+            hash_map::Entry::Occupied(_) => {
                 return Err(io::Error::new(io::ErrorKind::AddrInUse, "port already bound"));
             }
         }
@@ -158,140 +1206,544 @@ impl Interface {
         drop(cm);
         Ok(TcpListener {
             port, 
+            closed: AtomicBool::new(false),
+            nonblocking: AtomicBool::new(false),
             h: self.ih.as_mut().unwrap().clone()
         })
     }
+
+    /// Open a UDP socket on `port`.
+    pub fn bind_udp(&mut self, port: u16) -> io::Result<UdpSocket> {
+        UdpSocket::bind(self.ih.as_ref().unwrap().clone(), port)
+    }
+
+    /// Open a connection to `addr`, blocking until the handshake completes.
+    ///
+    /// In a simulation this returns right away, and reads and writes fail until the handshake
+    /// completes.
+    pub fn connect(&mut self, addr: impl Into<SocketAddr>) -> io::Result<TcpStream> {
+        let stream = self.open(addr.into())?;
+        let ih = self.ih.as_ref().unwrap();
+        if !ih.can_block() {
+            // the handshake goes on in the background
+            return Ok(stream);
+        }
+
+        let mut shard = ih.shard(&stream.quad);
+        loop {
+            if let Some(r) = stream.connected(&mut shard) {
+                // dropping the stream takes the lock
+                drop(shard);
+                return r.map(|()| stream);
+            }
+            // the handshake completing makes the connection writable
+            let var = shard.wait_list(stream.quad).threads.clone();
+            shard = var.wait(shard).unwrap();
+        }
+    }
+
+    /// Send a SYN to `addr`. The handshake goes on in the background.
+    fn open(&mut self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let ih = self.ih.as_ref().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        if cm.terminate.is_some() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "interface is shutting down"));
+        }
+
+        let remote = (addr.ip(), addr.port());
+        let src = ih.config.source(remote.0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "interface has no address of this family"))?;
+        let local = (src, cm.ephemeral_port(ih, src, remote)?);
+        let quad = Quad {
+            src: remote,
+            dst: local,
+        };
+        let mut c = tcp::Connection::connect(&ih.config, ih.clock.now(), local, remote);
+        if let Some(mtu) = cm.paths.get(remote.0) {
+            c.on_path_mtu(ih.clock.now(), mtu);
+        }
+        ih.shard(&quad).connections.insert(quad, c);
+        // the SYN doesn't have to wait for the tick
+        ih.poke(quad);
+        Ok(TcpStream {
+            quad,
+            nonblocking: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+            #[cfg(feature = "test-hooks")]
+            wakeups: AtomicU64::new(0),
+            h: ih.clone(),
+        })
+    }
+
+    /// Tear down every connection and stop the packet threads.
+    ///
+    /// Listeners stop accepting immediately, and any thread blocked on a stream or listener of
+    /// this interface is woken up with an error once its connection is gone. Returns the error
+    /// that stopped a packet thread, if any.
+    pub fn shutdown(mut self, how: Teardown) -> io::Result<()> {
+        self.terminate(how)
+    }
+
+    fn terminate(&mut self, how: Teardown) -> io::Result<()> {
+        let ih = match self.ih.take() {
+            Some(ih) => ih,
+            None => return Ok(()),
+        };
+
+        let mut cm = ih.manager.lock().unwrap();
+        let now = ih.clock.now();
+        let deadline = match how {
+            Teardown::Graceful(timeout) => now + timeout,
+            Teardown::Abort => now,
+        };
+        cm.terminate.get_or_insert(deadline);
+        ih.manager_busy.store(true, Ordering::Release);
+        // no more connections will be accepted, and late SYNs get a RST
+        cm.pending.clear();
+        let threads = cm.wake_listeners();
+        drop(cm);
+        notify(threads);
+        drop(ih);
+
+        // a simulation drives this interface without threads, and finishes the teardown as
+        // time goes on
+        let mut r = Ok(());
+        for jh in self.jh.drain(..) {
+            let e = jh.join().map_err(|_| io::Error::other("packet thread panicked")).and_then(|r| r);
+            if r.is_ok() {
+                r = e;
+            }
+        }
+        r
+    }
 }
 
 
 impl Drop for Interface {
     fn drop(&mut self) {
-        self.ih.as_mut().unwrap().manager.lock().unwrap().terminate = true;
+        if let Err(e) = self.terminate(Teardown::Abort) {
+            eprintln!("Interface terminated with error {:?}", e);
+        }
+    }
+}
+
+/// Wait on `var` until it is notified or `deadline` passes, whichever comes first. `None` once
+/// the deadline passed.
+fn wait_until<'a, T>(var: &Condvar, guard: MutexGuard<'a, T>, deadline: Option<Instant>) -> Option<MutexGuard<'a, T>> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            Some(var.wait_timeout(guard, deadline - now).unwrap().0)
+        }
+        None => Some(var.wait(guard).unwrap()),
+    }
+}
 
-        drop(self.ih.take());
-        self.jh.take().expect("Interface dropped more than one").join().unwrap().unwrap();
+/// Like std, a zero timeout is refused rather than taken to mean no timeout
+fn check_timeout(timeout: Option<Duration>) -> io::Result<Option<Duration>> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot set a 0 duration timeout"));
     }
+    Ok(timeout)
 }
 
 pub struct TcpStream {
     quad: Quad, 
+    /// whether reads and writes fail with `WouldBlock` instead of waiting
+    nonblocking: AtomicBool,
+    /// how long reads and writes wait, if not forever
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+    /// how often blocked reads and writes woke up
+    #[cfg(feature = "test-hooks")]
+    wakeups: AtomicU64,
     h: InterfaceHandle
 }
 
-impl Read for TcpStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut cm = self.h.manager.lock().unwrap();
-        loop {
-            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated unexpectedly"))?;
+impl TcpStream {
+    fn connection<'a>(&self, shard: &'a mut Shard) -> io::Result<&'a mut tcp::Connection> {
+        shard.connections.get_mut(&self.quad).ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated unexpectedly"))
+    }
+
+    /// Let `f` take what it wants of the received data, or `None` if we have to wait for the
+    /// peer. `f` is only called if there is some, and returns how many bytes it took.
+    fn receive_now(&self, shard: &mut Shard, f: &mut dyn FnMut(&mut VecDeque<u8>) -> usize) -> Option<io::Result<usize>> {
+        let c = match self.connection(shard) {
+            Ok(c) => c,
+            Err(e) => return Some(Err(e)),
+        };
 
-            if c.is_rcv_closed() && c.incoming.is_empty() {
+        if c.incoming.is_empty() {
+            if let Some(e) = c.error {
+                return Some(Err(io::Error::new(e, "connection failed")));
+            }
+            if c.is_rcv_closed() || c.read_closed {
                 // no more data to read, no need to block
-                return Ok(0);
+                return Some(Ok(0));
             }
+            shard.reset_fd(&self.quad);
+            return None;
+        }
 
+        let closed = c.window_closed();
+        let n = f(&mut c.incoming);
+        if closed && n > 0 {
+            self.h.poke(self.quad);
+        }
+        Some(Ok(n))
+    }
 
-            if !c.incoming.is_empty() {
-                let mut nread = 0;
-                let (head, tail) = c.incoming.as_slices();
-                let hread = std::cmp::min(buf.len(), head.len());
-                buf.copy_from_slice(&head[..hread]);
-                nread += hread;
-    
-                let tread = std::cmp::min(buf.len() - nread, tail.len());
-                buf.copy_from_slice(&tail[..tread]);
-                nread += tread;
-                drop(c.incoming.drain(..nread));
-    
-                return Ok(nread);    
+    /// Wait until the connection has something for `f`, like `read` does.
+    fn receive(&self, f: &mut dyn FnMut(&mut VecDeque<u8>) -> usize) -> io::Result<usize> {
+        let deadline = self.read_timeout()?.map(|t| Instant::now() + t);
+        let mut shard = self.h.shard(&self.quad);
+        loop {
+            if let Some(r) = self.receive_now(&mut shard, f) {
+                return r;
             }
 
-            
-            cm = self.h.rcv_var.wait(cm).unwrap();
-            
+            if !self.h.can_block() || self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data to read"));
+            }
+            shard = self.wait(shard, deadline)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "read timed out"))?;
         }
     }
-}
 
-impl Write for TcpStream { 
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated unexpectedly"))?;
+    /// Wait until the connection changes, or `None` once `deadline` passed
+    fn wait<'a>(&self, mut shard: MutexGuard<'a, Shard>, deadline: Option<Instant>) -> Option<MutexGuard<'a, Shard>> {
+        let var = shard.wait_list(self.quad).threads.clone();
+        let shard = wait_until(&var, shard, deadline)?;
+        #[cfg(feature = "test-hooks")]
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+        Some(shard)
+    }
 
-        if c.unacked.len() >= SENDQUEUE_SIZE {
-            // TODO: block
-            return Err(io::Error::new(io::ErrorKind::WouldBlock, "too many bytes buffered"));
+    /// Queue up what fits of `buf` for sending, or `None` if the send buffer is full.
+    fn write_now(&self, shard: &mut Shard, buf: &[u8]) -> Option<io::Result<usize>> {
+        let c = match self.connection(shard) {
+            Ok(c) => c,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if let Some(e) = c.error {
+            return Some(Err(io::Error::new(e, "connection failed")));
+        }
+        if c.is_snd_closed() {
+            return Some(Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream was shut down for writing")));
+        }
+
+        let send_buffer_size = self.h.config.opts.send_buffer_size;
+        if c.unacked.len() >= send_buffer_size {
+            shard.reset_fd(&self.quad);
+            return None;
         }
 
-        let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
+        let nwrite = std::cmp::min(buf.len(), send_buffer_size - c.unacked.len());
         c.unacked.extend(buf[..nwrite].iter());
+        if nwrite > 0 {
+            self.h.poke(self.quad);
+        }
 
-        // TODO: wake up writer
+        Some(Ok(nwrite))
+    }
 
-        Ok(nwrite)
+    /// How the handshake of a connection we opened went, or `None` while it goes on
+    fn connected(&self, shard: &mut Shard) -> Option<io::Result<()>> {
+        let c = match self.connection(shard) {
+            Ok(c) => c,
+            Err(e) => return Some(Err(e)),
+        };
+        if let Some(e) = c.error {
+            shard.connections.remove(&self.quad);
+            return Some(Err(io::Error::new(e, "could not connect")));
+        }
+        if c.is_established() {
+            return Some(Ok(()));
+        }
+        None
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated unexpectedly"))?;
+    /// Whether the peer acknowledged everything we wrote
+    fn flushed(&self, shard: &mut Shard) -> io::Result<bool> {
+        let c = self.connection(shard)?;
+        if let Some(e) = c.error {
+            return Err(io::Error::new(e, "connection failed"));
+        }
+        Ok(c.unacked.is_empty())
+    }
+}
 
-        if c.unacked.is_empty()  {
-            // TODO: block
-            return Ok(())
+/// Copy as much from the front of `incoming` as fits into `buf`, wherever it wraps around.
+fn copy_front(incoming: &VecDeque<u8>, buf: &mut [u8]) -> usize {
+    let (head, tail) = incoming.as_slices();
+    let hread = std::cmp::min(buf.len(), head.len());
+    buf[..hread].copy_from_slice(&head[..hread]);
+
+    let tread = std::cmp::min(buf.len() - hread, tail.len());
+    buf[hread..hread + tread].copy_from_slice(&tail[..tread]);
+    hread + tread
+}
+
+/// Move as much from the front of `incoming` as fits into `buf`.
+fn take_front(incoming: &mut VecDeque<u8>, buf: &mut [u8]) -> usize {
+    let nread = copy_front(incoming, buf);
+    drop(incoming.drain(..nread));
+    nread
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.receive(&mut |incoming| take_front(incoming, buf))
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        self.receive(&mut |incoming| {
+            let mut nread = 0;
+            for buf in bufs.iter_mut() {
+                nread += take_front(incoming, buf);
+            }
+            nread
+        })
+    }
+}
+
+impl Write for TcpStream { 
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = self.write_timeout()?.map(|t| Instant::now() + t);
+        let mut shard = self.h.shard(&self.quad);
+        loop {
+            if let Some(r) = self.write_now(&mut shard, buf) {
+                return r;
+            }
+
+            if !self.h.can_block() || self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "too many bytes buffered"));
+            }
+            // until the peer acknowledges some of what is buffered
+            shard = self.wait(shard, deadline)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "write timed out"))?;
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut shard = self.h.shard(&self.quad);
+        if self.flushed(&mut shard)? {
+            Ok(())
         } else {
             // TODO: block
-            return Err(io::Error::new(io::ErrorKind::WouldBlock, "outgoing not empty"))
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "outgoing not empty"))
         }
     }
 }
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
-        let mut cm = self.h.manager.lock().unwrap();
-        // TODO: send FYN on cm.connections[quad]
-        // TODO: _eventually_ remove self.quad from cm.connections
+        let mut shard = self.h.shard(&self.quad);
+        if let Some(c) = shard.connections.get_mut(&self.quad) {
+            // the packet loop forgets the connection once the FIN exchange is over
+            c.close();
+            c.released = true;
+        }
+        shard.waiters.remove(&self.quad);
+        drop(shard);
+        self.h.poke(self.quad);
     }
 }
 
 impl TcpStream {
+    /// In non-blocking mode, reads that would wait for the peer and writes that would wait for
+    /// room in the send buffer fail with `WouldBlock`.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// How often reads and writes blocked on this stream woke up to look again, for tests that
+    /// check that nothing else wakes them.
+    #[doc(hidden)]
+    #[cfg(feature = "test-hooks")]
+    pub fn wakeups(&self) -> u64 {
+        self.wakeups.load(Ordering::Relaxed)
+    }
+
+    /// The workers that processed the connection so far, for tests that check it stays with one.
+    #[doc(hidden)]
+    #[cfg(feature = "test-hooks")]
+    pub fn workers(&self) -> Vec<usize> {
+        let mut shard = self.h.shard(&self.quad);
+        self.connection(&mut shard).map(|c| c.workers.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// Like `read`, but leave what was read in the stream for the next read.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.receive(&mut |incoming| copy_front(incoming, buf))
+    }
+
+    /// Read without copying: wait for data like `read` does, then lend the oldest of it to `f`,
+    /// which returns how many of the bytes it consumed, at most as many as it got. Those are
+    /// gone from the stream, and their count is returned. At the end of the stream `f` isn't
+    /// called, and this returns 0.
+    ///
+    /// `f` may get only part of what was received, when it wraps around the end of the receive
+    /// buffer. It runs with the connection locked, so it must not use this stream.
+    pub fn read_with(&mut self, f: impl FnOnce(&[u8]) -> usize) -> io::Result<usize> {
+        let mut f = Some(f);
+        self.receive(&mut |incoming| {
+            let f = f.take().expect("called once there is data");
+            let lent = incoming.as_slices().0;
+            // anything past what it saw isn't for `f` to consume
+            let nread = std::cmp::min(f(lent), lent.len());
+            drop(incoming.drain(..nread));
+            nread
+        })
+    }
+
+    /// Let reads that wait longer than `timeout` fail with `TimedOut`. `None` waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    /// Let writes that wait longer than `timeout` fail with `TimedOut`. `None` waits forever.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.write_timeout.lock().unwrap() = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+
     pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
-        // TODO: send FYN
-        unimplemented!();
+        let mut shard = self.h.shard(&self.quad);
+        let c = self.connection(&mut shard)?;
+
+        if let std::net::Shutdown::Read | std::net::Shutdown::Both = how {
+            c.read_closed = true;
+            c.incoming.clear();
+        }
+        if let std::net::Shutdown::Write | std::net::Shutdown::Both = how {
+            c.close();
+        }
+        let threads = shard.wake(&self.quad, tcp::Available::READ);
+        drop(shard);
+        notify(threads);
+        self.h.poke(self.quad);
+        Ok(())
     }
 }
 
 pub struct TcpListener {
     port: u16, 
+    closed: AtomicBool,
+    /// whether `accept` fails with `WouldBlock` instead of waiting
+    nonblocking: AtomicBool,
     h: InterfaceHandle
 }
 
 impl TcpListener {
-    pub fn accept(&mut self) -> io::Result<TcpStream> {
+    /// Take the next connection that came in, or `None` if there is none yet.
+    fn accept_now(&self, cm: &mut ConnectionManager) -> Option<io::Result<TcpStream>> {
+        if self.closed.load(Ordering::Acquire) {
+            return Some(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "listener was closed")));
+        }
+
+        let pending = match cm.pending.get_mut(&self.port) {
+            Some(pending) => pending,
+            None => return Some(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "listener was closed"))),
+        };
+        let quad = match pending.pop_front() {
+            Some(quad) => quad,
+            None => {
+                if let Some(w) = cm.listeners.get(&self.port) {
+                    w.reset_fd();
+                }
+                return None;
+            }
+        };
+        Some(Ok(TcpStream {
+            quad,
+            nonblocking: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+            #[cfg(feature = "test-hooks")]
+            wakeups: AtomicU64::new(0),
+            h: self.h.clone()
+        }))
+    }
+
+    pub fn accept(&self) -> io::Result<TcpStream> {
+        self.accept_until(None)
+    }
+
+    /// Like `accept`, but fails with `TimedOut` when no connection comes in within `timeout`.
+    pub fn accept_timeout(&self, timeout: Duration) -> io::Result<TcpStream> {
+        self.accept_until(Some(Instant::now() + timeout))
+    }
+
+    fn accept_until(&self, deadline: Option<Instant>) -> io::Result<TcpStream> {
         let mut cm = self.h.manager.lock().unwrap();
 
         loop {
-            if let Some(quad) = cm.pending.get_mut(&self.port).expect("port closed while listener active").pop_front() {
-                return Ok(TcpStream {
-                    quad, 
-                    h: self.h.clone()
-                });
-            } 
-            
-            cm = self.h.pending_var.wait(cm).unwrap();
-                
+            if let Some(r) = self.accept_now(&mut cm) {
+                return r;
+            }
+
+            if !self.h.can_block() || self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no pending connection"));
+            }
+            let var = cm.listener(self.port).threads.clone();
+            cm = wait_until(&var, cm, deadline)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "accept timed out"))?;
         }
+    }
 
-       
+    /// In non-blocking mode, `accept` fails with `WouldBlock` when no connection is pending.
+    /// Accepted streams start out blocking.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stop listening on this port.
+    ///
+    /// Every connection that was not yet accepted is reset, later SYNs to the port are answered
+    /// with a RST, and threads blocked in `accept` return an error.
+    pub fn close(&self) -> io::Result<()> {
+        let mut cmg = self.h.manager.lock().unwrap();
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let cm = &mut *cmg;
+        if let Some(pending) = cm.pending.remove(&self.port) {
+            for quad in pending {
+                if let Some(c) = self.h.shard(&quad).connections.remove(&quad) {
+                    cm.aborted.push(c);
+                    self.h.manager_busy.store(true, Ordering::Release);
+                }
+            }
+        }
+        let threads = cm.wake_accept(self.port);
+        drop(cmg);
+        notify(threads);
+        Ok(())
     }
 }
 
 impl Drop for TcpListener {
     fn drop(&mut self) {
+        let _ = self.close();
         let mut cm = self.h.manager.lock().unwrap();
-        let pending = cm.pending.remove(&self.port).expect("Port closed");
-        
-        for _quad in pending {
-            // TODO: terminate cm.connections[quad]
-            unimplemented!();
-        }
+        cm.listeners.remove(&self.port);
     }
-}
\ No newline at end of file
+}
@@ -1,11 +1,17 @@
+use std::env;
 use std::io;
 use std::thread;
 use std::io::prelude::*;
 
 
 fn main() -> io::Result<()>{
-    let mut i = trust::Interface::new()?;
-    let mut l1 = i.bind(7000)?;
+    let mut builder = trust::InterfaceBuilder::new();
+    // impair what we send the way netem would, e.g. TRUST_NETEM="delay 20ms loss 1%"
+    if let Ok(netem) = env::var("TRUST_NETEM") {
+        builder = builder.impair(netem.parse()?);
+    }
+    let mut i = builder.build()?;
+    let l1 = i.bind(7000)?;
     //let mut l2 = i.bind(7001)?;
     let jh1 = thread::spawn(move || {
         while let Ok(mut stream) = l1.accept() {
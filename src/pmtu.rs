@@ -0,0 +1,177 @@
+//! Path MTU discovery: what routers tell us with ICMP (RFC 1191), and probing for paths that
+//! drop big packets without a word (RFC 4821).
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Smallest MTU we go down to over IPv4, whatever a router claims: every link carries 68 bytes
+/// (RFC 791)
+const MIN_MTU: usize = 68;
+/// Every link that carries IPv6 takes packets of 1280 bytes (RFC 8200 S5)
+const MIN_MTU6: usize = 1280;
+/// How long a lowered path MTU holds before we try larger packets again (RFC 1191 S6.3)
+const TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Where the search starts over when big packets vanish (RFC 4821 S7.2)
+const BASE_MTU: usize = 1024;
+/// The search is over once it narrowed the path MTU down this far
+const GRANULARITY: usize = 32;
+
+/// Smallest MTU we go down to for packets to `dst`
+pub(crate) fn min_mtu(dst: IpAddr) -> usize {
+    match dst {
+        IpAddr::V4(_) => MIN_MTU,
+        IpAddr::V6(_) => MIN_MTU6,
+    }
+}
+
+/// MTUs that ICMP "fragmentation needed" and ICMPv6 "packet too big" messages told us about, by
+/// destination
+#[derive(Default)]
+pub(crate) struct Paths {
+    // ordered, so that a simulation expires them the same way every run
+    mtus: BTreeMap<IpAddr, (usize, Instant)>,
+}
+
+impl Paths {
+    /// The path MTU to `dst`, if we learned one
+    pub(crate) fn get(&self, dst: IpAddr) -> Option<usize> {
+        self.mtus.get(&dst).map(|&(mtu, _)| mtu)
+    }
+
+    /// Packets larger than `mtu` don't make it to `dst`. Path MTUs only go up by timing out.
+    pub(crate) fn lower(&mut self, now: Instant, dst: IpAddr, mtu: usize) {
+        if self.get(dst).is_none_or(|known| mtu < known) {
+            self.mtus.insert(dst, (mtu, now + TIMEOUT));
+        }
+    }
+
+    /// Forget the path MTUs we learned too long ago.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.mtus.retain(|_, &mut (_, deadline)| deadline > now);
+    }
+
+    /// Whether there is nothing to expire
+    pub(crate) fn is_empty(&self) -> bool {
+        self.mtus.is_empty()
+    }
+}
+
+/// Old routers leave the next-hop MTU out of their messages (RFC 1191 S5). Guess it from the
+/// size of the packet they dropped, with the table of common MTUs of RFC 1191 S7.
+pub(crate) fn plateau(too_big: usize) -> usize {
+    const PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+    PLATEAUS.iter().copied().find(|&p| p < too_big).unwrap_or(MIN_MTU)
+}
+
+/// The size of the packets of one connection, and the search for a larger one (RFC 4821)
+pub(crate) struct Search {
+    /// largest packet we send, known or assumed to get through
+    pub(crate) mtu: usize,
+    /// smallest packet known not to get through, or the largest we ever send
+    high: usize,
+    /// the largest we ever send: the link MTU, or what the peer's MSS allows
+    max: usize,
+    /// probe in flight: the ACK that covers it, and its size
+    probe: Option<(u32, usize)>,
+    /// when to look for a larger MTU again, after we had to lower it
+    raise_at: Option<Instant>,
+    /// the MTU every link on the path has, which we never go below
+    min: usize,
+}
+
+impl Search {
+    pub(crate) fn new(mtu: usize, min: usize) -> Self {
+        Search {
+            mtu,
+            high: mtu,
+            max: mtu,
+            probe: None,
+            raise_at: None,
+            min,
+        }
+    }
+
+    /// Never send packets larger than `max`.
+    pub(crate) fn limit(&mut self, max: usize) {
+        self.max = std::cmp::min(self.max, max);
+        self.high = std::cmp::min(self.high, max);
+        self.mtu = std::cmp::min(self.mtu, max);
+    }
+
+    /// A router told us that packets larger than `mtu` don't fit. Returns whether we have to
+    /// send smaller packets now.
+    pub(crate) fn lower(&mut self, now: Instant, mtu: usize) -> bool {
+        if self.probe.is_some_and(|(_, size)| size > mtu) {
+            self.probe = None;
+        }
+        self.high = std::cmp::min(self.high, mtu);
+        if mtu >= self.mtu {
+            return false;
+        }
+        self.mtu = mtu;
+        self.raise_at = Some(now + TIMEOUT);
+        true
+    }
+
+    /// Our packets keep getting lost, and it might be a router that drops the big ones without
+    /// telling us.
+    pub(crate) fn black_hole(&mut self, now: Instant) {
+        let base = std::cmp::max(BASE_MTU, self.min);
+        let mtu = if self.mtu > base { base } else { std::cmp::max(self.mtu / 2, self.min) };
+        if mtu == self.mtu {
+            return;
+        }
+        self.high = self.mtu;
+        self.mtu = mtu;
+        self.probe = None;
+        self.raise_at = Some(now + TIMEOUT);
+    }
+
+    /// Size of the probe to send next, if we are searching and no probe is in flight
+    pub(crate) fn next_probe(&mut self, now: Instant) -> Option<usize> {
+        if self.raise_at.is_some_and(|at| now >= at) {
+            // the path may have changed since
+            self.high = self.max;
+            self.raise_at = None;
+        }
+        if self.probe.is_some() || self.high - self.mtu < GRANULARITY {
+            return None;
+        }
+        Some((self.mtu + self.high) / 2)
+    }
+
+    /// A probe of `size` went out, and the ACK up to `end` covers it.
+    pub(crate) fn probe_sent(&mut self, end: u32, size: usize) {
+        self.probe = Some((end, size));
+    }
+
+    /// Everything up to `ackn` arrived, and with it maybe our probe.
+    pub(crate) fn on_ack(&mut self, now: Instant, ackn: u32) {
+        if let Some((end, size)) = self.probe {
+            if !crate::tcp::wrapping_lt(ackn, end) {
+                self.probe = None;
+                self.mtu = size;
+                self.search_done(now);
+            }
+        }
+    }
+
+    /// We had to send data again. Returns whether a probe was in flight, which we take as lost.
+    pub(crate) fn on_timeout(&mut self, now: Instant) -> bool {
+        match self.probe.take() {
+            Some((_, size)) => {
+                self.high = size;
+                self.search_done(now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn search_done(&mut self, now: Instant) {
+        if self.high - self.mtu < GRANULARITY && self.mtu < self.max {
+            self.raise_at.get_or_insert(now + TIMEOUT);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Waiting on many streams and listeners at once.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Wake, Waker};
+use std::time::{Duration, Instant};
+
+use bitflags::bitflags;
+
+use crate::{tcp, ConnectionManager, InterfaceHandle, Quad, Shard, TcpListener, TcpStream};
+
+bitflags! {
+    /// What a stream or listener is ready for, or what a `Poller` waits for.
+    pub struct Ready: u8 {
+        /// `read` won't wait: there is data, the peer closed its side, or the stream failed
+        const READABLE = 0b00001;
+        /// `write` takes data
+        const WRITABLE = 0b00010;
+        /// `accept` won't wait
+        const ACCEPTABLE = 0b00100;
+        /// the peer won't send more, or the connection or listener is gone. Always reported.
+        const HUP = 0b01000;
+        /// the connection failed. Always reported.
+        const ERROR = 0b10000;
+    }
+}
+
+/// A stream or listener that is ready for something, and the key it was registered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub key: usize,
+    pub ready: Ready,
+}
+
+enum Source {
+    Stream(Quad),
+    Listener(u16),
+}
+
+struct Registration {
+    h: InterfaceHandle,
+    source: Source,
+    interest: Ready,
+}
+
+/// What wakes up a waiting `Poller`
+#[derive(Default)]
+struct Signal {
+    woken: Mutex<bool>,
+    var: Condvar,
+}
+
+impl Wake for Signal {
+    fn wake(self: Arc<Self>) {
+        *self.woken.lock().unwrap() = true;
+        self.var.notify_all();
+    }
+}
+
+/// Waits until any of the streams and listeners registered with it is ready.
+///
+/// Readiness is level-triggered: a stream that is readable is reported by every `poll` until it
+/// is read from. A stream or listener should be registered with at most one poller, and not be
+/// waited on by an async task at the same time.
+#[derive(Default)]
+pub struct Poller {
+    sources: HashMap<usize, Registration>,
+    signal: Arc<Signal>,
+}
+
+impl Poller {
+    pub fn new() -> Self {
+        Poller::default()
+    }
+
+    /// Watch `stream` for `interest` under `key`, replacing whatever was registered under it.
+    pub fn register_stream(&mut self, stream: &TcpStream, key: usize, interest: Ready) {
+        self.sources.insert(key, Registration {
+            h: stream.h.clone(),
+            source: Source::Stream(stream.quad),
+            interest,
+        });
+    }
+
+    /// Watch `listener` for `interest` under `key`, replacing whatever was registered under it.
+    pub fn register_listener(&mut self, listener: &TcpListener, key: usize, interest: Ready) {
+        self.sources.insert(key, Registration {
+            h: listener.h.clone(),
+            source: Source::Listener(listener.port),
+            interest,
+        });
+    }
+
+    /// Stop watching whatever was registered under `key`.
+    pub fn deregister(&mut self, key: usize) {
+        self.sources.remove(&key);
+    }
+
+    /// Wait until something registered is ready, or `timeout` passes, and replace `events` with
+    /// what is ready. Waits forever without a timeout.
+    ///
+    /// In a simulation this never waits.
+    pub fn poll(&mut self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let waker = Waker::from(self.signal.clone());
+        let can_block = self.sources.values().all(|r| r.h.can_block());
+        loop {
+            // anything that happens from here on wakes us up
+            *self.signal.woken.lock().unwrap() = false;
+
+            events.clear();
+            for (&key, r) in &self.sources {
+                let ready = match r.source {
+                    Source::Stream(quad) => {
+                        let mut shard = r.h.shard(&quad);
+                        let ready = stream_readiness(&shard, &quad) & (r.interest | Ready::HUP | Ready::ERROR);
+                        if ready.is_empty() {
+                            let w = shard.wait_list(quad);
+                            // failures wake up readers too
+                            w.read = Some(waker.clone());
+                            if r.interest.contains(Ready::WRITABLE) {
+                                w.write = Some(waker.clone());
+                            }
+                        }
+                        ready
+                    }
+                    Source::Listener(port) => {
+                        let mut cm = r.h.manager.lock().unwrap();
+                        let ready = listener_readiness(&cm, port) & (r.interest | Ready::HUP | Ready::ERROR);
+                        if ready.is_empty() {
+                            cm.listener(port).read = Some(waker.clone());
+                        }
+                        ready
+                    }
+                };
+                if !ready.is_empty() {
+                    events.push(Event { key, ready });
+                }
+            }
+            if !events.is_empty() || !can_block {
+                return Ok(());
+            }
+
+            let mut woken = self.signal.woken.lock().unwrap();
+            while !*woken {
+                woken = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Ok(());
+                        }
+                        self.signal.var.wait_timeout(woken, deadline - now).unwrap().0
+                    }
+                    None => self.signal.var.wait(woken).unwrap(),
+                };
+            }
+        }
+    }
+}
+
+fn stream_readiness(shard: &Shard, quad: &Quad) -> Ready {
+    let c = match shard.connections.get(quad) {
+        Some(c) => c,
+        // the interface is gone
+        None => return Ready::READABLE | Ready::WRITABLE | Ready::HUP | Ready::ERROR,
+    };
+
+    let a = c.availability();
+    let mut ready = Ready::empty();
+    if a.contains(tcp::Available::READ) {
+        ready |= Ready::READABLE;
+    }
+    if a.contains(tcp::Available::WRITE) {
+        ready |= Ready::WRITABLE;
+    }
+    if c.is_rcv_closed() {
+        ready |= Ready::HUP;
+    }
+    if c.error.is_some() {
+        ready |= Ready::ERROR;
+    }
+    ready
+}
+
+fn listener_readiness(cm: &ConnectionManager, port: u16) -> Ready {
+    match cm.pending.get(&port) {
+        Some(pending) if pending.is_empty() => Ready::empty(),
+        Some(_) => Ready::ACCEPTABLE,
+        // closed, or the interface is gone
+        None => Ready::ACCEPTABLE | Ready::HUP,
+    }
+}
@@ -0,0 +1,164 @@
+//! Reassembly of fragmented IPv4 datagrams (RFC 791 S3.2).
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::checksum::Checksum;
+
+/// How long we wait for the rest of a datagram once a fragment arrived (Linux's `ipfrag_time`)
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes that incomplete datagrams may take up all together
+const MEMORY_LIMIT: usize = 1 << 20;
+/// Largest datagram payload the total length field can describe, whatever the header length
+const MAX_PAYLOAD: usize = 65535 - 60;
+
+/// Fragments belong to the same datagram if all of these match (RFC 791 S3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+}
+
+struct Datagram {
+    /// header of the first fragment, once it arrived
+    header: Option<Vec<u8>>,
+    data: Vec<u8>,
+    /// the ranges of `data` that arrived, sorted and merged
+    have: Vec<(usize, usize)>,
+    /// length of the payload, once the last fragment arrived
+    len: Option<usize>,
+    /// when we give up on the datagram
+    deadline: Instant,
+}
+
+impl Datagram {
+    fn is_complete(&self) -> bool {
+        self.header.is_some() && self.len.is_some_and(|len| self.have == [(0, len)])
+    }
+}
+
+/// Incomplete datagrams, waiting for their missing fragments
+#[derive(Default)]
+pub(crate) struct Reassembler {
+    // ordered, so that a simulation evicts the same datagrams every run
+    datagrams: BTreeMap<Key, Datagram>,
+    /// bytes taken up by `datagrams`
+    held: usize,
+    /// datagrams we gave up on
+    pub(crate) failed: u64,
+}
+
+impl Reassembler {
+    /// Add the fragment with header `iph` and `payload`, and return the whole datagram once it is
+    /// complete.
+    pub(crate) fn add(&mut self, now: Instant, iph: &etherparse::Ipv4HeaderSlice, payload: &[u8]) -> Option<Vec<u8>> {
+        let key = Key {
+            src: iph.source_addr(),
+            dst: iph.destination_addr(),
+            protocol: iph.protocol(),
+            id: iph.identification(),
+        };
+        let start = iph.fragments_offset() as usize * 8;
+        let end = start + payload.len();
+
+        // all but the last fragment carry a multiple of 8 bytes, and no fragment reaches past
+        // what the total length field allows
+        if (iph.more_fragments() && !payload.len().is_multiple_of(8)) || end > MAX_PAYLOAD {
+            self.drop_datagram(&key);
+            return None;
+        }
+
+        let d = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            header: None,
+            data: Vec::new(),
+            have: Vec::new(),
+            len: None,
+            deadline: now + TIMEOUT,
+        });
+
+        // the last fragment fixes the length, and nothing may lie beyond it
+        let len = if iph.more_fragments() { d.len } else { Some(end) };
+        let beyond = |len: usize| end > len || d.have.last().is_some_and(|&(_, e)| e > len) || d.len.is_some_and(|l| l != len);
+        if len.is_some_and(beyond) {
+            self.drop_datagram(&key);
+            return None;
+        }
+
+        // a fragment that is sent again is fine, but overlapping ones that don't agree are how
+        // attacks slip data past filters, so give up on the datagram instead of picking one.
+        // `have` is merged, so a duplicate may lie inside a range made of several fragments.
+        if let Some(&(s, e)) = d.have.iter().find(|&&(s, e)| start < e && s < end) {
+            if start < s || e < end || d.data[start..end] != *payload {
+                self.drop_datagram(&key);
+            }
+            return None;
+        }
+
+        if d.data.len() < end {
+            self.held += end - d.data.len();
+            d.data.resize(end, 0);
+        }
+        d.data[start..end].copy_from_slice(payload);
+        d.len = len;
+        if start == 0 {
+            d.header = Some(iph.slice().to_vec());
+        }
+        let i = d.have.partition_point(|&(s, _)| s < start);
+        d.have.insert(i, (start, end));
+        d.have = d.have.iter().fold(Vec::new(), |mut merged: Vec<(usize, usize)>, &(s, e)| {
+            match merged.last_mut() {
+                Some(last) if last.1 == s => last.1 = e,
+                _ => merged.push((s, e)),
+            }
+            merged
+        });
+
+        if d.is_complete() {
+            let d = self.datagrams.remove(&key).unwrap();
+            self.held -= d.data.len();
+            return Some(whole(d));
+        }
+
+        // make room by giving up on the oldest datagrams, possibly this one
+        while self.held > MEMORY_LIMIT {
+            let oldest = self.datagrams.iter().min_by_key(|(_, d)| d.deadline).map(|(k, _)| *k)?;
+            self.drop_datagram(&oldest);
+        }
+        None
+    }
+
+    /// Give up on the datagrams whose fragments took too long.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        let expired: Vec<Key> = self.datagrams.iter().filter(|(_, d)| d.deadline <= now).map(|(k, _)| *k).collect();
+        for key in expired {
+            self.drop_datagram(&key);
+        }
+    }
+
+    fn drop_datagram(&mut self, key: &Key) {
+        if let Some(d) = self.datagrams.remove(key) {
+            self.held -= d.data.len();
+            self.failed += 1;
+        }
+    }
+}
+
+/// The reassembled datagram, under the header of the first fragment
+fn whole(d: Datagram) -> Vec<u8> {
+    let mut packet = d.header.unwrap();
+    let hlen = packet.len();
+    let total = (hlen + d.data.len()) as u16;
+    packet[2..4].copy_from_slice(&total.to_be_bytes());
+    // keep DF, clear MF and the offset
+    packet[6] &= 0x40;
+    packet[7] = 0;
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let mut sum = Checksum::default();
+    sum.add(&packet);
+    packet[10..12].copy_from_slice(&sum.finish().to_be_bytes());
+    packet.extend_from_slice(&d.data);
+    packet
+}
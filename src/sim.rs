@@ -0,0 +1,277 @@
+//! Deterministic simulation of a network of interfaces.
+//!
+//! The interfaces of a `Simulation` share a virtual clock and talk over a link with
+//! `Impairments`. Nothing runs in the background: time only moves in `Simulation::step`, and
+//! every random decision comes from the seed, so running the same code with the same seed
+//! replays the same packets at the same instants.
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::device::Device;
+use crate::impair::{Impairments, Shaper};
+use crate::{Clock, C, Interface, InterfaceBuilder, InterfaceHandle, TICK};
+
+/// The time of a simulation, shared with its interfaces
+pub(crate) struct VirtualClock {
+    base: Instant,
+    /// nanoseconds since `base`
+    elapsed: AtomicU64,
+}
+
+impl VirtualClock {
+    pub(crate) fn now(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed.load(Ordering::Acquire))
+    }
+
+    fn set(&self, elapsed: Duration) {
+        self.elapsed.store(elapsed.as_nanos() as u64, Ordering::Release);
+    }
+}
+
+/// Device of a simulated interface, which only collects what the stack sends
+struct Outbox {
+    packets: Vec<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Device for Outbox {
+    fn recv(&mut self, _buf: &mut [u8], _deadline: Instant) -> io::Result<Option<usize>> {
+        // the simulation hands packets to the stack itself
+        Ok(None)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.mtu {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "packet is larger than the MTU"));
+        }
+        self.packets.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+struct Host {
+    ih: InterfaceHandle,
+    nic: Outbox,
+    /// cleared once the interface shut down
+    running: bool,
+}
+
+/// A network of interfaces that runs on virtual time.
+///
+/// Sockets of simulated interfaces never block: calls that would wait for the network fail with
+/// `WouldBlock` instead, and `connect` returns before the handshake is done. Drive the network
+/// with `step`, `run_for` or `run_until` in between.
+pub struct Simulation {
+    clock: Arc<VirtualClock>,
+    hosts: Vec<Host>,
+    /// the link between all hosts
+    wire: Shaper,
+    seed: u64,
+    next_tick: Duration,
+    /// every packet delivered so far, and when
+    trace: Vec<(Duration, Vec<u8>)>,
+    /// addresses the test plays itself
+    peers: Vec<IpAddr>,
+    /// packets that reached a peer, and when
+    received: Vec<(Duration, Vec<u8>)>,
+}
+
+impl Simulation {
+    /// A network whose link treats packets according to `link`. The seed of `link` is ignored
+    /// in favour of `seed`.
+    pub fn new(seed: u64, link: Impairments) -> Self {
+        Simulation {
+            clock: Arc::new(VirtualClock {
+                base: Instant::now(),
+                elapsed: AtomicU64::new(0),
+            }),
+            hosts: Vec::new(),
+            wire: Shaper::with_seed(link, seed),
+            seed,
+            next_tick: Duration::default(),
+            trace: Vec::new(),
+            peers: Vec::new(),
+            received: Vec::new(),
+        }
+    }
+
+    /// Add an interface to the network. Packets are routed to it by its addresses; the device
+    /// name, queues, impairments and Ethernet settings of the builder are ignored.
+    pub fn add_host(&mut self, builder: InterfaceBuilder) -> io::Result<Interface> {
+        let nic = Outbox {
+            packets: Vec::new(),
+            mtu: builder.mtu.unwrap_or(1500),
+        };
+        let mut config = builder.config(&nic, 1)?;
+        // initial sequence numbers come from the seed and the virtual clock too
+        config.isn_key = self.seed;
+        config.isn_epoch = self.clock.base;
+        self.claim(config.local.0.into())?;
+        if let Some((addr, _)) = config.local6 {
+            self.claim(addr.into())?;
+        }
+
+        // the simulation ticks on its own schedule
+        let ih = C::new(config, Clock::Virtual(self.clock.clone()), vec![None]);
+        self.hosts.push(Host {
+            ih: ih.clone(),
+            nic,
+            running: true,
+        });
+        Ok(Interface {
+            ih: Some(ih),
+            jh: Vec::new(),
+        })
+    }
+
+    /// Add a host at `addr` that the test plays itself, with `send` and `take_received`.
+    pub fn add_peer(&mut self, addr: impl Into<IpAddr>) -> io::Result<()> {
+        let addr = addr.into();
+        self.claim(addr)?;
+        self.peers.push(addr);
+        Ok(())
+    }
+
+    fn claim(&self, addr: IpAddr) -> io::Result<()> {
+        if self.peers.contains(&addr) || self.hosts.iter().any(|h| h.ih.config.owns(addr)) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another host already has this address"));
+        }
+        Ok(())
+    }
+
+    /// Put a raw IP packet on the link, as if a peer sent it just now.
+    pub fn send(&mut self, packet: Vec<u8>) {
+        self.wire.push(self.clock.now(), packet);
+    }
+
+    /// Take the packets that reached peers so far, with the time they arrived at.
+    pub fn take_received(&mut self) -> Vec<(Duration, Vec<u8>)> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// Virtual time since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// The current virtual time, as the interfaces see it
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Every packet delivered so far, with the time it arrived at.
+    pub fn trace(&self) -> &[(Duration, Vec<u8>)] {
+        &self.trace
+    }
+
+    /// Time of the next event
+    fn next_event(&self) -> Duration {
+        match self.wire.next_due() {
+            Some(at) => std::cmp::min(self.next_tick, at - self.clock.base),
+            None => self.next_tick,
+        }
+    }
+
+    /// Advance to the next event, which is either a packet arriving or the next tick of the
+    /// interfaces, and handle it.
+    pub fn step(&mut self) -> io::Result<()> {
+        let t = self.next_event();
+        self.clock.set(t);
+        let now = self.clock.now();
+
+        while let Some(packet) = self.wire.pop(now) {
+            self.deliver(now, &packet)?;
+            self.trace.push((t, packet));
+        }
+
+        if t == self.next_tick {
+            for host in self.hosts.iter_mut().filter(|h| h.running) {
+                match crate::on_tick(&mut host.nic, &host.ih, now, 0) {
+                    Ok(true) => {}
+                    r => {
+                        crate::finish(&host.ih);
+                        host.running = false;
+                        r?;
+                    }
+                }
+            }
+            self.next_tick += TICK;
+        }
+
+        self.transmit();
+        Ok(())
+    }
+
+    /// Run until `duration` of virtual time has passed, including what happens at the very end.
+    pub fn run_for(&mut self, duration: Duration) -> io::Result<()> {
+        let end = self.elapsed() + duration;
+        while self.next_event() <= end {
+            self.step()?;
+        }
+        self.clock.set(end);
+        Ok(())
+    }
+
+    /// Run until `done` returns true, checking it before every step, but for no longer than
+    /// `limit` of virtual time. Returns whether `done` was reached.
+    pub fn run_until(&mut self, limit: Duration, mut done: impl FnMut() -> bool) -> io::Result<bool> {
+        let end = self.elapsed() + limit;
+        loop {
+            if done() {
+                return Ok(true);
+            }
+            if self.elapsed() >= end {
+                return Ok(false);
+            }
+            self.step()?;
+        }
+    }
+
+    fn deliver(&mut self, now: Instant, packet: &[u8]) -> io::Result<()> {
+        let dst: IpAddr = match packet.first().map(|b| b >> 4) {
+            Some(4) => match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+                Ok(iph) => iph.destination_addr().into(),
+                Err(_) => return Ok(()),
+            },
+            Some(6) => match etherparse::Ipv6HeaderSlice::from_slice(packet) {
+                Ok(iph) => iph.destination_addr().into(),
+                Err(_) => return Ok(()),
+            },
+            // unroutable
+            _ => return Ok(()),
+        };
+
+        if self.peers.contains(&dst) {
+            self.received.push((self.clock.elapsed(), packet.to_vec()));
+        } else if let Some(host) = self.hosts.iter_mut().find(|h| h.running && h.ih.config.owns(dst)) {
+            if let Err(e) = crate::on_packet(&mut host.nic, &host.ih, now, packet) {
+                crate::finish(&host.ih);
+                host.running = false;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Put whatever the interfaces sent on the wire.
+    fn transmit(&mut self) {
+        let now = self.clock.now();
+        for host in &mut self.hosts {
+            for packet in host.nic.packets.drain(..) {
+                self.wire.push(now, packet);
+            }
+        }
+    }
+}
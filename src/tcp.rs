@@ -1,33 +1,45 @@
 use std::io;
 use std::io::Write;
 use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 use bitflags::bitflags;
+use crate::device::Device;
+use crate::{ip, pmtu};
 
+/// TCP's number in the protocol field of the IP header
+pub(crate) const PROTOCOL: u8 = 6;
 
 bitflags! {
     pub(crate) struct Available: u8 {
         const READ = 0b00000001;
         const WRITE = 0b00000010;
-
+        /// the peer acknowledged everything we sent
+        const FLUSHED = 0b00000100;
     }
 }
 
 #[derive(Debug)]
 enum State {
-    //Closed,
+    Closed,
     //Listen,
+    SynSent,
     SynRcvd,
     Estab,
     FinWait1,
     FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
     TimeWait,
 }
 
 impl State {
     fn is_synchronized(&self) -> bool {
         match *self {
-            State::SynRcvd => false,
-            State::Estab | State::FinWait1 | State::FinWait2 | State::TimeWait => true,
+            State::Closed | State::SynSent | State::SynRcvd => false,
+            State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait |
+                State::Closing | State::LastAck | State::TimeWait => true,
         }
     }
 }
@@ -36,37 +48,193 @@ pub struct Connection {
     state: State,
     recv: RecvSequenceSpace,
     send: SendSequenceSpace,
-    ip: etherparse::Ipv4Header,
+    ip: ip::Header,
     tcp: etherparse::TcpHeader,
+    /// largest payload we put in one segment: the smaller of our and the peer's MSS
+    mss: usize,
+    /// our MSS, announced on our SYN
+    our_mss: u16,
+    /// size of the packets that make it to the peer
+    pmtu: pmtu::Search,
+    /// how many bytes `incoming` may hold
+    rcv_buf: usize,
+    /// how much the user may queue in `unacked`
+    snd_buf: usize,
 
     pub (crate) incoming: VecDeque<u8>,
     pub (crate) unacked: VecDeque<u8>,
+
+    /// the user is done writing, so send a FIN once everything in `unacked` went out
+    closed: bool,
+    /// sequence number of our FIN, once we sent it
+    closed_at: Option<u32>,
+    /// the user is done reading, so drop whatever else arrives
+    pub(crate) read_closed: bool,
+    /// no TcpStream refers to this connection anymore
+    pub(crate) released: bool,
+    /// why the connection died, reported by the next read or write
+    pub(crate) error: Option<io::ErrorKind>,
+    /// what ICMP last told us went wrong, reported instead of a timeout if we give up
+    soft_error: Option<io::ErrorKind>,
+
+    timers: Timers,
+
+    /// the workers that processed this connection
+    #[cfg(feature = "test-hooks")]
+    pub(crate) workers: std::collections::BTreeSet<usize>,
+}
+
+/// Initial retransmission timeout (RFC 6298 S2.1)
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// RFC 6298 asks for 1s, but like most stacks we go lower
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// how many timeouts in a row we put up with before giving up on the peer
+const MAX_RETRIES: u32 = 8;
+/// after this many timeouts in a row, suspect a path that drops big packets (RFC 4821 S7.2)
+const BLACK_HOLE_RETRIES: u32 = 2;
+/// maximum segment lifetime: RFC 793 suggests two minutes, like Linux we assume 30 seconds
+const MSL: Duration = Duration::from_secs(30);
+
+/// Retransmission timer state (RFC 6298)
+struct Timers {
+    /// smoothed round-trip time, once we took a sample
+    srtt: Option<Duration>,
+    /// round-trip time variation
+    rttvar: Duration,
+    /// retransmission timeout
+    rto: Duration,
+    /// when to retransmit, while there is anything unacknowledged
+    rtx: Option<Instant>,
+    /// segment timed for the next RTT sample: the ACK that covers it and when it was sent
+    probe: Option<(u32, Instant)>,
+    /// timeouts in a row without progress
+    retries: u32,
+    /// after a timeout, the next sequence number to send again
+    resend: Option<u32>,
+    /// when TIME-WAIT is over
+    time_wait: Option<Instant>,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Timers {
+            srtt: None,
+            rttvar: Duration::default(),
+            rto: INITIAL_RTO,
+            rtx: None,
+            probe: None,
+            retries: 0,
+            resend: None,
+            time_wait: None,
+        }
+    }
+}
+
+impl Timers {
+    fn sample_rtt(&mut self, r: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(r);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + r / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + std::cmp::max(Duration::from_millis(1), self.rttvar * 4);
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
 }
 
 impl Connection {
     pub(crate) fn is_rcv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // TODO: any state after rcvd fin: CLOSE-WAIT, LAST-ACK, CLOSED, CLOSING
-            true
-        } else {
-            false
-        }
+        // any state after we received a FIN
+        matches!(self.state, State::CloseWait | State::Closing | State::LastAck | State::TimeWait | State::Closed)
+    }
+
+    /// Whether the user can no longer queue data to send.
+    pub(crate) fn is_snd_closed(&self) -> bool {
+        self.closed || !matches!(self.state, State::SynSent | State::SynRcvd | State::Estab | State::CloseWait)
+    }
+
+    /// Whether the handshake has completed.
+    pub(crate) fn is_established(&self) -> bool {
+        self.state.is_synchronized()
+    }
+
+    /// Whether the connection has finished closing and can be forgotten.
+    pub(crate) fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    /// Whether the connection only waits out TIME-WAIT, to answer a retransmitted FIN and to keep
+    /// old duplicates from being taken for a new incarnation of it.
+    pub(crate) fn is_time_wait(&self) -> bool {
+        matches!(self.state, State::TimeWait)
     }
 
-    fn availability(&self) -> Available {
-        // TODO: Take into account self.state
+    /// Both FINs have been acknowledged: linger for 2 MSL before forgetting the connection
+    /// (RFC 793 S3.5)
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.state = State::TimeWait;
+        self.timers.time_wait = Some(now + 2 * MSL);
+    }
+
+    pub(crate) fn availability(&self) -> Available {
+        if self.error.is_some() {
+            // wake up everyone to collect the error
+            return Available::all();
+        }
+
         let mut a = Available::empty();
-        if self.is_rcv_closed() || !self.incoming.is_empty() {
+        if self.is_rcv_closed() || self.read_closed || !self.incoming.is_empty() {
             a |= Available::READ;
         }
-        // TODO: set Available::WRITE
+        if matches!(self.state, State::Estab | State::CloseWait) && !self.closed && self.unacked.len() < self.snd_buf {
+            a |= Available::WRITE;
+        }
+        if self.unacked.is_empty() {
+            a |= Available::FLUSHED;
+        }
         a
     }
+
+    /// Largest payload that goes into one segment on the path to the peer
+    fn seg_size(&self) -> usize {
+        std::cmp::min(self.mss, self.pmtu.mtu - self.headers())
+    }
+
+    /// Bytes of IP and TCP header in front of the payload of our data segments
+    fn headers(&self) -> usize {
+        self.ip.header_len() + 20
+    }
+
+    /// Note down which worker is processing us, if it is one.
+    fn seen_by_worker(&mut self) {
+        #[cfg(feature = "test-hooks")]
+        if let Some(worker) = crate::WORKER.with(std::cell::Cell::get) {
+            self.workers.insert(worker);
+        }
+    }
+
+    /// Whether we told the peer there is no room, so it waits for us to say when there is
+    pub(crate) fn window_closed(&self) -> bool {
+        self.recv.wnd == 0
+    }
+
+    /// Window to advertise for the room left in `incoming`
+    fn recv_window(&self) -> u16 {
+        // without window scaling we can't advertise more than 64k
+        std::cmp::min(self.rcv_buf.saturating_sub(self.incoming.len()), u16::MAX as usize) as u16
+    }
 }
 
 /// State of Send Sequence Space (RFC 793 S3.2 F4)
 ///
-/// ```
+/// ```text
 ///         1         2          3          4
 ///     ----------|----------|----------|----------
 ///             SND.UNA    SND.NXT    SND.UNA
@@ -78,27 +246,28 @@ impl Connection {
 /// 4 - future sequence numbers which are not yet allowed
 /// ```
 #[derive(Default)]
+#[allow(dead_code)]
 struct SendSequenceSpace {
     /// send unacknowledged
-    una: u32, 
+    una: u32,
     /// send next
-    nxt: u32, 
+    nxt: u32,
     /// send window
-    wnd: u16, 
+    wnd: u16,
     /// send urgent pointer
-    up: bool,  
-    /// SSeqNumber for last window update 
+    up: bool,
+    /// SSeqNumber for last window update
     wl1: u32,
     /// SAckNumber for last window update
-    wl2: u32, 
+    wl2: u32,
     /// intial send sequence number
-    iss: u32, 
+    iss: u32,
 }
 
 
 /// State of Receive Sequence Space (RFC 793 S3.2 F5)
 ///
-/// ```
+/// ```text
 ///                        1          2          3
 ///                    ----------|----------|----------
 ///                           RCV.NXT    RCV.NXT
@@ -109,6 +278,7 @@ struct SendSequenceSpace {
 ///         3 - future sequence numbers which are not yet allowed
 /// ```
 #[derive(Default)]
+#[allow(dead_code)]
 struct RecvSequenceSpace {
     /// receive next
     nxt: u32,
@@ -120,118 +290,454 @@ struct RecvSequenceSpace {
     irs: u32,
 }
 
+/// RFC 6528: a clock ticking every 4 microseconds plus a keyed hash of the connection, so
+/// sequence numbers of a new incarnation of the quad don't overlap the old one and can't be
+/// guessed from other connections
+fn initial_sequence_number(cfg: &crate::Config, now: Instant, local: (IpAddr, u16), remote: (IpAddr, u16)) -> u32 {
+    use std::hash::{Hash, Hasher};
+    let m = (now.saturating_duration_since(cfg.isn_epoch).as_micros() / 4) as u32;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (local, remote, cfg.isn_key).hash(&mut hasher);
+    m.wrapping_add(hasher.finish() as u32)
+}
+
+/// MSS the peer announced on its SYN
+fn peer_mss(tcph: &etherparse::TcpHeaderSlice) -> u16 {
+    tcph.options_iterator()
+        .find_map(|o| match o {
+            Ok(etherparse::TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+            _ => None,
+        })
+        // RFC 1122 S4.2.2.6: assume 536 unless the peer tells us otherwise
+        .unwrap_or(536)
+}
+
 impl Connection {
+    fn new(cfg: &crate::Config, local: (IpAddr, u16), remote: (IpAddr, u16), state: State, iss: u32) -> Self {
+        let mut c = Connection {
+            state,
+            recv: RecvSequenceSpace::default(),
+            send: SendSequenceSpace {
+                iss,
+                una: iss,
+                nxt: iss,
+                ..SendSequenceSpace::default()
+            },
+            ip: ip::Header::new(cfg.opts.ttl, PROTOCOL, local.0, remote.0),
+            tcp: etherparse::TcpHeader::new(
+                local.1,
+                remote.1,
+                iss,
+                0),
+            mss: 536,
+            our_mss: cfg.mss(remote.0),
+            pmtu: pmtu::Search::new(cfg.mtu, pmtu::min_mtu(remote.0)),
+            rcv_buf: cfg.opts.recv_buffer_size,
+            snd_buf: cfg.opts.send_buffer_size,
+            incoming: VecDeque::default(),
+            unacked: VecDeque::default(),
+            closed: false,
+            closed_at: None,
+            read_closed: false,
+            released: false,
+            error: None,
+            soft_error: None,
+            timers: Timers::default(),
+            #[cfg(feature = "test-hooks")]
+            workers: Default::default(),
+        };
+        c.seen_by_worker();
+        // we'd rather hear that a packet is too big than have it fragmented (RFC 1191)
+        c.ip.dont_fragment();
+        c.recv.wnd = c.recv_window();
+        c
+    }
+
     pub fn accept<'a>(
-        nic: &mut tun_tap::Iface, 
-        iph: etherparse::Ipv4HeaderSlice<'a>, 
-        tcph: etherparse::TcpHeaderSlice<'a>, 
-        data: &'a [u8]) -> io::Result<Option<Self>> {
-
-        let mut buf = [0u8; 1500];
-            
-            if !tcph.syn() {
+        nic: &mut impl Device,
+        now: Instant,
+        cfg: &crate::Config,
+        packet: &ip::Packet<'a>,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        _data: &'a [u8]) -> io::Result<Option<Self>> {
+
+            if !tcph.syn() || tcph.ack() || tcph.rst() {
                 // only expecting SYN packet
                 return Ok(None);
             }
-            
-            let iss = 0;
-            let wnd = 1024;
-
-            let mut c = Connection {
-                state: State::SynRcvd,
-                recv: RecvSequenceSpace {
-                    irs: tcph.sequence_number(),
-                    nxt: tcph.sequence_number() + 1,
-                    wnd: tcph.window_size(),
-                    up: false,
-                },
-                send: SendSequenceSpace {
-                    iss,
-                    una: iss,
-                    nxt: iss,
-                    wnd: wnd,
-                    up: false,
-                    wl1: 0,
-                    wl2: 0
-                }, 
-                ip: etherparse::Ipv4Header::new(
-                    0,
-                    64, 
-                    etherparse::IpTrafficClass::Tcp, 
-                    iph.destination_addr().octets(), 
-                    iph.source_addr().octets()),
-                tcp: etherparse::TcpHeader::new(
-                    tcph.destination_port(), 
-                    tcph.source_port(), 
-                    iss, 
-                    wnd),
-                incoming: VecDeque::default(),
-                unacked: VecDeque::default(),
-            };
-            // keep track of sender info
-            
 
-            // decide on what to send
-            
+            let local = (packet.dst, tcph.destination_port());
+            let remote = (packet.src, tcph.source_port());
+            let iss = initial_sequence_number(cfg, now, local, remote);
+            let mut c = Connection::new(cfg, local, remote, State::SynRcvd, iss);
+
+            // keep track of sender info
+            c.recv.irs = tcph.sequence_number();
+            c.recv.nxt = tcph.sequence_number().wrapping_add(1);
+            c.send.wnd = tcph.window_size();
+            c.send.wl1 = tcph.sequence_number();
+            c.mss = std::cmp::min(c.our_mss, peer_mss(&tcph)) as usize;
+            c.pmtu.limit(c.mss + c.headers());
 
             // start establishing connection
-            c.tcp.syn = true;
             c.tcp.ack = true;
-            c.write(nic, &[])?;
+            c.send_syn(nic, now)?;
             Ok(Some(c))
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, payload: &[u8]) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
-        
-        self.tcp.sequence_number = self.send.nxt;
-        self.tcp.acknowledgment_number = self.recv.nxt;
+    /// Start an active open. The SYN goes out on the next tick.
+    pub(crate) fn connect(cfg: &crate::Config, now: Instant, local: (IpAddr, u16), remote: (IpAddr, u16)) -> Self {
+        let iss = initial_sequence_number(cfg, now, local, remote);
+        Connection::new(cfg, local, remote, State::SynSent, iss)
+    }
+
+    fn send_syn(&mut self, nic: &mut impl Device, now: Instant) -> io::Result<()> {
+        self.tcp.syn = true;
+        self.tcp.set_options(&[etherparse::TcpOptionElement::MaximumSegmentSize(self.our_mss)]).expect("Can't set MSS option");
+        self.write(nic, now, self.send.iss, 0)?;
+        // MSS is only valid on SYN segments
+        self.tcp.set_options(&[]).expect("Can't clear options");
+        Ok(())
+    }
+
+    /// Send a segment starting at `seq` with up to `limit` bytes from `unacked`. Callers keep
+    /// `limit` within the segment size.
+    ///
+    /// Control flags are taken from `self.tcp`; SYN and FIN are cleared once they went out.
+    fn write(&mut self, nic: &mut impl Device, now: Instant, seq: u32, limit: usize) -> io::Result<usize> {
+        let mut buf = vec![0u8; self.ip.header_len() + self.tcp.header_len() as usize + std::cmp::min(limit, self.unacked.len())];
 
-        let size = std::cmp::min(buf.len(), self.tcp.header_len() as usize + self.ip.header_len() as usize + payload.len());
+        self.tcp.sequence_number = seq;
+        self.tcp.acknowledgment_number = self.recv.nxt;
+        self.recv.wnd = self.recv_window();
+        self.tcp.window_size = self.recv.wnd;
 
-        self.ip.set_payload_len(size - self.ip.header_len()).expect("Could not set payload len");
+        // our SYN takes up the first sequence number but has no byte in unacked
+        let mut offset = seq.wrapping_sub(self.send.una) as usize;
+        if self.send.una == self.send.iss {
+            offset = offset.saturating_sub(1);
+        }
+        let start = std::cmp::min(offset, self.unacked.len());
+        let end = std::cmp::min(start + limit, self.unacked.len());
 
-        // the kernel computes checksum for us
-        self.tcp.checksum = self.tcp.calc_checksum_ipv4(&self.ip, &[]).expect("Failed to compute checksum");
+        self.ip.set_payload_len(self.tcp.header_len() as usize + end - start);
 
-        // write out the headers
+        // write out the headers, and the payload straight out of the send queue
+        self.tcp.checksum = 0;
         let mut unwritten = &mut buf[..];
-        self.ip.write(&mut unwritten).expect("Can't write ip header");
+        self.ip.write(&mut unwritten);
         self.tcp.write(&mut unwritten)?;
-        let payload_bytes = unwritten.write(payload)?;
+        let mut payload_bytes = 0;
+        let (head, tail) = self.unacked.as_slices();
+        let split = head.len();
+        let head = &head[std::cmp::min(start, split)..std::cmp::min(end, split)];
+        let tail = &tail[start.saturating_sub(split)..end.saturating_sub(split)];
+        for part in [head, tail] {
+            payload_bytes += unwritten.write(part)?;
+        }
         let unwritten = unwritten.len();
-        self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32);
+        let len = buf.len() - unwritten;
+        fill_checksum(nic, &self.ip, &mut buf[self.ip.header_len()..len]);
 
+        let mut next_seq = seq.wrapping_add(payload_bytes as u32);
         if self.tcp.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
+            next_seq = next_seq.wrapping_add(1);
             self.tcp.syn = false;
         }
         if self.tcp.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
+            next_seq = next_seq.wrapping_add(1);
             self.tcp.fin = false;
         }
+        if next_seq != seq {
+            // this needs to be acked, so make sure we come back to it
+            self.timers.rtx.get_or_insert(now + self.timers.rto);
+            if wrapping_lt(self.send.nxt, next_seq) && self.timers.probe.is_none() {
+                // time new segments only, retransmissions give ambiguous samples (Karn)
+                self.timers.probe = Some((next_seq, now));
+            }
+        }
+        if wrapping_lt(self.send.nxt, next_seq) {
+            self.send.nxt = next_seq;
+        }
 
-        nic.send(&buf[..buf.len() - unwritten])?;
+        nic.send(&buf[..len])?;
         Ok(payload_bytes)
     }
 
-    fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        // TODO: Fix Sequence numbers here
-        // TODO: hande synchronized reset
+    pub(crate) fn send_rst(&mut self, nic: &mut impl Device, now: Instant) -> io::Result<()> {
+        // SEQ is SND.NXT and ACK is RCV.NXT
+        self.tcp.rst = true;
+        self.write(nic, now, self.send.nxt, 0)?;
+        self.tcp.rst = false;
+        self.state = State::Closed;
+        Ok(())
+    }
+
+    /// Answer an unacceptable ACK with <SEQ=SEG.ACK><CTL=RST>, leaving our own state alone
+    fn reject_ack(&mut self, nic: &mut impl Device, now: Instant, ackn: u32) -> io::Result<()> {
+        let (nxt, ack) = (self.send.nxt, self.tcp.ack);
         self.tcp.rst = true;
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
-        self.write(nic, &[])?;
+        self.tcp.ack = false;
+        self.write(nic, now, ackn, 0)?;
+        self.tcp.rst = false;
+        self.tcp.ack = ack;
+        self.send.nxt = nxt;
         Ok(())
+    }
 
+    /// Start an active close: our FIN goes out once all queued data has been sent.
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+        if let State::SynSent = self.state {
+            // the peer hasn't heard anything from us but a SYN, so just forget about it
+            self.state = State::Closed;
+        }
+    }
+
+    /// Send whatever is due without waiting for a segment to arrive.
+    pub(crate) fn on_tick(&mut self, nic: &mut impl Device, now: Instant) -> io::Result<()> {
+        self.seen_by_worker();
+        if self.is_time_wait() && self.timers.time_wait.is_some_and(|end| now >= end) {
+            self.state = State::Closed;
+        }
+        if self.is_closed() || self.is_time_wait() {
+            return Ok(());
+        }
+
+        if self.timers.rtx.is_some_and(|deadline| now >= deadline) {
+            self.timers.retries += 1;
+            if self.timers.retries > MAX_RETRIES {
+                // the peer is gone
+                self.state = State::Closed;
+                self.error = Some(self.soft_error.unwrap_or(io::ErrorKind::TimedOut));
+                return Ok(());
+            }
+            // back off (RFC 6298 S5.5), and don't trust samples of what we send again
+            self.timers.rto = std::cmp::min(self.timers.rto * 2, MAX_RTO);
+            let full = std::cmp::min(self.unacked.len(), self.send.wnd as usize) >= self.seg_size();
+            if !self.pmtu.on_timeout(now) && self.timers.retries == BLACK_HOLE_RETRIES && full {
+                // full segments keep getting lost: try smaller ones in case they don't fit
+                self.pmtu.black_hole(now);
+            }
+            self.timers.probe = None;
+            self.timers.rtx = None;
+            self.timers.resend = Some(self.send.una);
+        }
+
+        if let Some(seq) = self.timers.resend.take() {
+            self.resend(nic, now, seq)?;
+        }
+
+        if let State::SynSent = self.state {
+            if self.send.nxt == self.send.iss {
+                self.send_syn(nic, now)?;
+            }
+            return Ok(());
+        }
+        if !self.state.is_synchronized() {
+            return Ok(());
+        }
+
+        if self.recv.wnd == 0 && self.recv_window() > 0 {
+            // the user made room after we closed the window, tell the peer
+            self.write(nic, now, self.send.nxt, 0)?;
+        }
+
+        if self.closed_at.is_some() {
+            // everything has been sent
+            return Ok(());
+        }
+
+        let inflight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        let mut window = (self.send.wnd as usize).saturating_sub(inflight);
+        let mut unsent = self.unacked.len().saturating_sub(inflight);
+        if window == 0 && inflight == 0 && unsent > 0 {
+            // the peer closed its window: probe it with a byte so we learn when it opens again
+            window = 1;
+        }
+        let headers = self.headers();
+        let probe_payload = self.pmtu.next_probe(now).map(|size| size - headers);
+        if probe_payload.is_some_and(|payload| inflight > 0 && unsent >= payload && window < payload && payload <= self.send.wnd as usize) {
+            // a probe is due, and the ACKs of what is in flight will make room for it: wait for
+            // them rather than fill the window with smaller segments
+            return Ok(());
+        }
+        while unsent > 0 && window > 0 {
+            let mut limit = std::cmp::min(self.seg_size(), std::cmp::min(unsent, window));
+            let probe = self.pmtu.next_probe(now).filter(|&size| {
+                // a probe is a full segment of new data (RFC 4821 S7.4)
+                let payload = size - headers;
+                matches!(self.state, State::Estab | State::CloseWait) && unsent >= payload && window >= payload
+            });
+            if let Some(size) = probe {
+                limit = size - headers;
+            }
+            let n = self.write(nic, now, self.send.nxt, limit)?;
+            if let Some(size) = probe {
+                self.pmtu.probe_sent(self.send.nxt, size);
+            }
+            unsent -= n;
+            window -= n;
+        }
+
+        if self.closed && unsent == 0 {
+            let next = match self.state {
+                State::Estab => State::FinWait1,
+                State::CloseWait => State::LastAck,
+                _ => return Ok(()),
+            };
+            self.tcp.fin = true;
+            self.closed_at = Some(self.send.nxt);
+            self.write(nic, now, self.send.nxt, 0)?;
+            self.state = next;
+        }
+
+        Ok(())
+    }
+
+    /// Send everything from `seq` up to SND.NXT again, as far as the window allows.
+    fn resend(&mut self, nic: &mut impl Device, now: Instant, mut seq: u32) -> io::Result<()> {
+        // keep probing a closed window
+        let wend = self.send.una.wrapping_add(std::cmp::max(self.send.wnd as u32, 1));
+        while wrapping_lt(seq, self.send.nxt) {
+            if seq == self.send.iss && !self.state.is_synchronized() {
+                self.send_syn(nic, now)?;
+                seq = seq.wrapping_add(1);
+            } else if Some(seq) == self.closed_at {
+                self.tcp.fin = true;
+                self.write(nic, now, seq, 0)?;
+                seq = seq.wrapping_add(1);
+            } else if wrapping_lt(seq, wend) {
+                let end = self.closed_at.unwrap_or(self.send.nxt);
+                let limit = std::cmp::min(end.wrapping_sub(seq), wend.wrapping_sub(seq)) as usize;
+                let n = self.write(nic, now, seq, std::cmp::min(limit, self.seg_size()))?;
+                if n == 0 {
+                    break;
+                }
+                seq = seq.wrapping_add(n as u32);
+            } else {
+                // the rest has to wait for the window to move
+                self.timers.resend = Some(seq);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// SND.UNA moved up to `ackn`: take an RTT sample and rearm the retransmission timer.
+    fn on_ack_progress(&mut self, now: Instant, ackn: u32) {
+        if let Some((end, sent)) = self.timers.probe {
+            if !wrapping_lt(ackn, end) {
+                self.timers.sample_rtt(now - sent);
+                self.timers.probe = None;
+            }
+        }
+        self.timers.retries = 0;
+        self.soft_error = None;
+        self.pmtu.on_ack(now, ackn);
+        if let Some(seq) = self.timers.resend {
+            if wrapping_lt(seq, ackn) {
+                self.timers.resend = Some(ackn);
+            }
+        }
+        self.timers.rtx = if ackn == self.send.nxt {
+            None
+        } else {
+            Some(now + self.timers.rto)
+        };
+    }
+
+    /// Whether `seq` is a sequence number we sent that wasn't acknowledged yet, which is what a
+    /// genuine ICMP error about one of our segments quotes (RFC 5927 S4.1)
+    pub(crate) fn in_flight(&self, seq: u32) -> bool {
+        is_between_wrapped(self.send.una.wrapping_sub(1), seq, self.send.nxt)
+    }
+
+    /// Packets larger than `mtu` don't make it to the peer. Whatever we sent that is larger was
+    /// dropped, so send it again in smaller segments right away.
+    pub(crate) fn on_path_mtu(&mut self, now: Instant, mtu: usize) {
+        if self.pmtu.lower(now, mtu) && self.state.is_synchronized() && !self.unacked.is_empty() {
+            self.timers.resend = Some(self.send.una);
+        }
+    }
+
+    /// One of our segments could not be delivered (RFC 1122 S4.2.3.9).
+    pub(crate) fn on_icmp_error(&mut self, kind: io::ErrorKind, hard: bool) -> Available {
+        if hard && matches!(self.state, State::SynSent | State::SynRcvd) {
+            // no use in trying again
+            self.state = State::Closed;
+            self.error = Some(kind);
+        } else {
+            // the network may recover, and once synchronized we don't abort on an unauthenticated
+            // message (RFC 5461 S4)
+            self.soft_error = Some(kind);
+        }
+        self.availability()
+    }
+
+    /// Whether our FIN has been acknowledged
+    fn fin_acked(&self) -> bool {
+        self.closed_at.is_some_and(|fin| self.send.una == fin.wrapping_add(1))
+    }
+
+    /// RFC 793 S3.9 "If the state is SYN-SENT"
+    fn on_syn_sent(&mut self, nic: &mut impl Device, now: Instant, tcph: etherparse::TcpHeaderSlice) -> io::Result<Available> {
+        let ackn = tcph.acknowledgment_number();
+        if tcph.ack() && !is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1)) {
+            if !tcph.rst() {
+                self.reject_ack(nic, now, ackn)?;
+            }
+            return Ok(self.availability());
+        }
+
+        if tcph.rst() {
+            if tcph.ack() {
+                self.state = State::Closed;
+                self.error = Some(io::ErrorKind::ConnectionRefused);
+            }
+            return Ok(self.availability());
+        }
+
+        if !tcph.syn() {
+            return Ok(self.availability());
+        }
+
+        self.recv.irs = tcph.sequence_number();
+        self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        self.send.wnd = tcph.window_size();
+        self.send.wl1 = tcph.sequence_number();
+        self.send.wl2 = ackn;
+        self.mss = std::cmp::min(self.our_mss, peer_mss(&tcph)) as usize;
+        self.pmtu.limit(self.mss + self.headers());
+        self.tcp.ack = true;
+
+        if tcph.ack() {
+            // our SYN has been ACKed
+            self.send.una = ackn;
+            self.on_ack_progress(now, ackn);
+            self.state = State::Estab;
+            self.write(nic, now, self.send.nxt, 0)?;
+        } else {
+            // simultaneous open
+            self.state = State::SynRcvd;
+            self.send_syn(nic, now)?;
+        }
+        Ok(self.availability())
     }
 
     pub(crate) fn on_packet<'a>(
         &mut self,
-        nic: &mut tun_tap::Iface, 
-        iph: etherparse::Ipv4HeaderSlice<'a>, 
-        tcph: etherparse::TcpHeaderSlice<'a>, 
+        nic: &mut impl Device,
+        now: Instant,
+        tcph: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8]) -> io::Result<Available> {
+        self.seen_by_worker();
+        match self.state {
+            State::Closed => return Ok(self.availability()),
+            State::SynSent => return self.on_syn_sent(nic, now, tcph),
+            _ => {}
+        }
+
         // check sequence numbers are valid (RFC 793 S3.3)
         // valid segment check
         // Ok if it ACKs at least one byte
@@ -243,35 +749,42 @@ impl Connection {
         let okay = if slen == 0 {
             // 0-length segments has own rules for acceptance
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                    false
+                seqn == self.recv.nxt
             } else {
-                true
+                is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
             }
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) &&
-                    !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn.wrapping_add(slen - 1), wend) {
-            
-                false
-            } else {
-                true
-            }
+            // a segment with data needs a non-zero window and must start or end inside of it
+            self.recv.wnd != 0 &&
+                (is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) ||
+                 is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn.wrapping_add(slen - 1), wend))
         };
 
         if !okay {
-            self.write(nic,&[])?;
+            if self.is_time_wait() && tcph.fin() && !tcph.rst() {
+                // the peer missed our ACK of its FIN: acknowledge it again, and wait anew
+                self.enter_time_wait(now);
+            }
+            if !tcph.rst() {
+                self.write(nic, now, self.send.nxt, 0)?;
+            }
             return Ok(self.availability());
         }
 
-        self.recv.nxt = seqn.wrapping_add(slen);
-        // TODO if not acceptable, send ACK
+        if tcph.rst() {
+            // the peer aborted the connection
+            self.state = State::Closed;
+            self.error = Some(io::ErrorKind::ConnectionReset);
+            self.incoming.clear();
+            self.unacked.clear();
+            return Ok(self.availability());
+        }
+
+        if tcph.syn() {
+            // a SYN in the window is an error, but don't reset on its word alone (RFC 5961 S4)
+            self.write(nic, now, self.send.nxt, 0)?;
+            return Ok(self.availability());
+        }
 
         if !tcph.ack() {
             return Ok(self.availability());
@@ -282,59 +795,173 @@ impl Connection {
         let ackn = tcph.acknowledgment_number();
 
         if let State::SynRcvd = self.state {
-            if is_between_wrapped(self.send.una.wrapping_sub(1) , ackn, self.send.nxt.wrapping_add(1)) {
+            if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
                 // must have ACKed our SYN, since we detected at least one ACKed byte and we just sent one packet (SYN)
                 self.state = State::Estab;
             } else {
-                // TODO: RST
+                self.reject_ack(nic, now, ackn)?;
+                return Ok(self.availability());
             }
         }
 
-        if let  State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            
-            if is_between_wrapped(self.send.una , ackn, self.send.nxt.wrapping_add(1)) {
-                self.send.una = ackn;
+        if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+            let mut acked = ackn.wrapping_sub(self.send.una) as usize;
+            if self.send.una == self.send.iss {
+                // our SYN has no byte in unacked
+                acked -= 1;
+            }
+            if self.closed_at.is_some_and(|fin| ackn == fin.wrapping_add(1)) {
+                // and neither has our FIN
+                acked -= 1;
             }
-            
-            // TODO: accept data
-            assert!(data.is_empty());
+            drop(self.unacked.drain(..std::cmp::min(acked, self.unacked.len())));
+            self.send.una = ackn;
+            self.on_ack_progress(now, ackn);
+        } else if wrapping_lt(self.send.nxt, ackn) {
+            // ACKs something we haven't sent yet
+            self.write(nic, now, self.send.nxt, 0)?;
+            return Ok(self.availability());
+        }
 
-            if let State::Estab = self.state {
-                // terminate connection FOR NOW TODO!
-                // TODO needs to be stored in the restransmission queue
-                self.tcp.fin = true;
-                self.write(nic, &[])?;
-                self.state = State::FinWait1;
-            }          
+        // update the send window, unless the segment is older than the last update
+        if wrapping_lt(self.send.wl1, seqn) || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2)) {
+            if self.send.wnd == 0 && tcph.window_size() > 0 && self.send.una != self.send.nxt {
+                // the window opened: whatever we probed it with was dropped, so go again from there
+                self.timers.resend.get_or_insert(self.send.una);
+            }
+            self.send.wnd = tcph.window_size();
+            self.send.wl1 = seqn;
+            self.send.wl2 = ackn;
         }
 
-        if let State::FinWait1 = self.state {
-            if self.send.una == self.send.iss + 2 {
+        if self.fin_acked() {
+            match self.state {
                 // our FIN has been ACKed
-                self.state = State::FinWait2;
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(now),
+                State::LastAck => {
+                    self.state = State::Closed;
+                    return Ok(self.availability());
+                }
+                _ => {}
             }
         }
 
-        if tcph.fin() {
+        // accept data
+        if !data.is_empty() && matches!(self.state, State::Estab | State::FinWait1 | State::FinWait2) {
+            if wrapping_lt(self.recv.nxt, seqn) {
+                // out of order, ask again for what we're missing
+                self.write(nic, now, self.send.nxt, 0)?;
+                return Ok(self.availability());
+            }
+
+            // skip what we already have, and take only what fits
+            let skip = self.recv.nxt.wrapping_sub(seqn) as usize;
+            let fresh = &data[std::cmp::min(skip, data.len())..];
+            let n = std::cmp::min(fresh.len(), self.recv_window() as usize);
+            if !self.read_closed {
+                self.incoming.extend(&fresh[..n]);
+            }
+            self.recv.nxt = self.recv.nxt.wrapping_add(n as u32);
+
+            if n < fresh.len() {
+                // the rest didn't fit, and neither does a FIN behind it
+                self.write(nic, now, self.send.nxt, 0)?;
+                return Ok(self.availability());
+            }
+        }
+
+        if tcph.fin() && seqn.wrapping_add(data.len() as u32) == self.recv.nxt {
+            // the peer is done sending
+            self.recv.nxt = self.recv.nxt.wrapping_add(1);
             match self.state {
-                State::FinWait2 => {
-                    // we're done with connection
-                    self.write(nic, &[])?;
-                    self.state = State::TimeWait;
-                }
-                _ => unimplemented!()
+                State::SynRcvd | State::Estab => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                // we're done with connection
+                State::FinWait2 => self.enter_time_wait(now),
+                _ => {}
             }
         }
-        
+
+        if slen > 0 {
+            self.write(nic, now, self.send.nxt, 0)?;
+        }
+
         Ok(self.availability())
     }
 }
 
-fn wrapping_ls(lhs: u32, rhs: u32) -> bool {
-    // From RFC132
-    lhs.wrapping_sub(rhs) > 2^31
+pub(crate) fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
+    // From RFC1323:
+    //     TCP determines if a data segment is "old" or "new" by testing
+    //     whether its sequence number is within 2**31 bytes of the left edge
+    //     of the window, and if it is not, discarding the data as "old".  To
+    //     insure that new data is never mistakenly considered old and vice-
+    //     versa, the left edge of the sender's window has to be at most
+    //     2**31 away from the right edge of the receiver's window.
+    lhs.wrapping_sub(rhs) > (1 << 31)
 }
 
 fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
-    wrapping_ls(start, x) && wrapping_ls(x, end)
-}
\ No newline at end of file
+    wrapping_lt(start, x) && wrapping_lt(x, end)
+}
+
+/// Reply to a segment that does not belong to any connection (RFC 793 S3.4 "Reset Generation")
+pub(crate) fn send_rst_reply<'a>(
+    nic: &mut impl Device,
+    cfg: &crate::Config,
+    packet: &ip::Packet<'a>,
+    tcph: etherparse::TcpHeaderSlice<'a>,
+    data: &'a [u8]) -> io::Result<()> {
+
+    if tcph.rst() {
+        // never answer a RST with a RST
+        return Ok(());
+    }
+
+    let mut ip = ip::Header::new(cfg.opts.ttl, PROTOCOL, packet.dst, packet.src);
+    let mut tcp = etherparse::TcpHeader::new(
+        tcph.destination_port(),
+        tcph.source_port(),
+        0,
+        0);
+    tcp.rst = true;
+
+    if tcph.ack() {
+        // <SEQ=SEG.ACK><CTL=RST>
+        tcp.sequence_number = tcph.acknowledgment_number();
+    } else {
+        // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+        let mut slen = data.len() as u32;
+        if tcph.fin() { slen += 1; }
+        if tcph.syn() { slen += 1; }
+        tcp.ack = true;
+        tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(slen);
+    }
+
+    ip.set_payload_len(tcp.header_len() as usize);
+
+    let mut buf = vec![0u8; ip.header_len() + tcp.header_len() as usize];
+    let mut unwritten = &mut buf[..];
+    ip.write(&mut unwritten);
+    tcp.write(&mut unwritten)?;
+    let unwritten = unwritten.len();
+    let len = buf.len() - unwritten;
+    fill_checksum(nic, &ip, &mut buf[ip.header_len()..len]);
+    nic.send(&buf[..len])?;
+    Ok(())
+}
+
+/// Fill in the checksum of a TCP `segment` that was written with a zero checksum, or leave the
+/// rest of the work to the link if it can do it.
+fn fill_checksum(nic: &impl Device, ip: &ip::Header, segment: &mut [u8]) {
+    let mut sum = ip.pseudo_header(segment.len());
+    let checksum = if nic.fills_checksums() {
+        // like Linux's CHECKSUM_PARTIAL: the link sums up the segment on top of the pseudo header
+        sum.partial()
+    } else {
+        sum.add(segment);
+        sum.finish()
+    };
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+}
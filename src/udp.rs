@@ -0,0 +1,148 @@
+//! UDP sockets (RFC 768).
+
+use std::collections::{HashMap, VecDeque, hash_map};
+use std::io;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+
+use crate::{ip, InterfaceHandle};
+
+/// UDP's number in the protocol field of the IP header
+pub(crate) const PROTOCOL: u8 = 17;
+/// datagrams a socket queues up before it drops more, and that wait to be sent before
+/// `send_to` refuses more
+const MAX_QUEUED: usize = 128;
+
+/// The UDP sockets of an interface
+#[derive(Default)]
+pub(crate) struct Sockets {
+    /// received datagrams and who sent them, by local port
+    bound: HashMap<u16, VecDeque<(SocketAddr, Vec<u8>)>>,
+    /// datagrams the packet loop has yet to send
+    pub(crate) outgoing: VecDeque<Vec<u8>>,
+    /// set once the interface is gone
+    pub(crate) closed: bool,
+}
+
+/// What became of a datagram that came in
+pub(crate) enum Delivery {
+    Queued,
+    /// the socket has too much waiting already
+    Dropped,
+    /// no socket is bound to the port
+    Unbound,
+}
+
+impl Sockets {
+    pub(crate) fn deliver(&mut self, port: u16, from: SocketAddr, data: &[u8]) -> Delivery {
+        match self.bound.get_mut(&port) {
+            Some(queue) if queue.len() >= MAX_QUEUED => Delivery::Dropped,
+            Some(queue) => {
+                queue.push_back((from, data.to_vec()));
+                Delivery::Queued
+            }
+            None => Delivery::Unbound,
+        }
+    }
+}
+
+/// A UDP socket bound to a port of an `Interface`.
+pub struct UdpSocket {
+    port: u16,
+    h: InterfaceHandle,
+}
+
+impl UdpSocket {
+    pub(crate) fn bind(h: InterfaceHandle, port: u16) -> io::Result<Self> {
+        let mut sockets = h.udp.lock().unwrap();
+        if sockets.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "interface is shutting down"));
+        }
+        match sockets.bound.entry(port) {
+            hash_map::Entry::Vacant(v) => {
+                v.insert(VecDeque::new());
+            }
+            hash_map::Entry::Occupied(_) => {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "port already bound"));
+            }
+        }
+        drop(sockets);
+        Ok(UdpSocket { port, h })
+    }
+
+    /// Our IPv4 address and the port. The socket takes datagrams to our IPv6 address as well,
+    /// if the interface has one.
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddrV4::new(self.h.config.local.0, self.port).into()
+    }
+
+    /// Send `buf` as one datagram to `addr`. It goes out with the next tick of the interface.
+    ///
+    /// Datagrams are not fragmented, so they have to fit into the MTU.
+    pub fn send_to(&self, buf: &[u8], addr: impl Into<SocketAddr>) -> io::Result<usize> {
+        let addr = addr.into();
+        let src = self.h.config.source(addr.ip())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "interface has no address of this family"))?;
+        if buf.len() + ip::header_len(addr.ip()) + 8 > self.h.config.mtu {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "datagram is larger than the MTU allows"));
+        }
+
+        let mut sockets = self.h.udp.lock().unwrap();
+        if sockets.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "interface is shut down"));
+        }
+        if sockets.outgoing.len() >= MAX_QUEUED {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "too many datagrams queued"));
+        }
+        sockets.outgoing.push_back(datagram(&self.h.config, (src, self.port), addr, buf));
+        Ok(buf.len())
+    }
+
+    /// Take the next datagram and who sent it, blocking until one arrives. Whatever doesn't fit
+    /// into `buf` is discarded.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut sockets = self.h.udp.lock().unwrap();
+        loop {
+            if sockets.closed {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "interface is shut down"));
+            }
+            let queue = sockets.bound.get_mut(&self.port).expect("socket is bound");
+            if let Some((from, data)) = queue.pop_front() {
+                let n = std::cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                return Ok((n, from));
+            }
+
+            if !self.h.can_block() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no datagram to read"));
+            }
+            sockets = self.h.udp_var.wait(sockets).unwrap();
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.h.udp.lock().unwrap().bound.remove(&self.port);
+    }
+}
+
+/// `payload` from our address and port `src` to `dst`, as an IP packet
+fn datagram(cfg: &crate::Config, src: (IpAddr, u16), dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let ip = ip::Header::new(cfg.opts.ttl, PROTOCOL, src.0, dst.ip());
+    let len = (payload.len() + 8) as u16;
+    let mut segment = Vec::with_capacity(len as usize);
+    for field in [src.1, dst.port(), len, 0] {
+        segment.extend_from_slice(&field.to_be_bytes());
+    }
+    segment.extend_from_slice(payload);
+
+    let mut sum = ip.pseudo_header(segment.len());
+    sum.add(&segment);
+    // a zero checksum means there is none, and its one's complement twin takes its place
+    let checksum = match sum.finish() {
+        0 => 0xffff,
+        c => c,
+    };
+    segment[6..8].copy_from_slice(&checksum.to_be_bytes());
+    ip.packet(&segment)
+}
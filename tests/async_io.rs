@@ -0,0 +1,118 @@
+#![cfg(feature = "tokio")]
+
+use std::io;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use trust::async_io::{TcpListener, TcpStream};
+use trust::Teardown;
+
+mod common;
+use common::{interfaces, SERVER};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn connect_accept_read_write_close() {
+    let (mut client, mut server) = interfaces();
+    let listener = TcpListener::bind(&mut server, 80).unwrap();
+
+    // the server waits for the connection before the client even starts
+    let server_task = tokio::spawn(async move {
+        let mut stream = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"world").await.unwrap();
+        stream.flush().await.unwrap();
+
+        // the client shuts down its side after our reply
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    });
+
+    let mut stream = TcpStream::connect(&mut client, SocketAddrV4::new(SERVER, 80)).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"world");
+    stream.shutdown().await.unwrap();
+
+    // and the server closes its side once it saw our FIN
+    tokio::time::timeout(Duration::from_secs(5), server_task).await.unwrap().unwrap();
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn many_streams_on_few_threads() {
+    let (mut client, mut server) = interfaces();
+    let listener = TcpListener::bind(&mut server, 80).unwrap();
+
+    // more waiting readers than worker threads, which blocking reads would deadlock on
+    let server_task = tokio::spawn(async move {
+        let mut echoes = Vec::new();
+        for _ in 0..8 {
+            let mut stream = listener.accept().await.unwrap();
+            echoes.push(tokio::spawn(async move {
+                let mut buf = [0u8; 64];
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    stream.write_all(&buf[..n]).await.unwrap();
+                }
+                stream.shutdown().await.unwrap();
+            }));
+        }
+        for echo in echoes {
+            echo.await.unwrap();
+        }
+    });
+
+    let mut streams = Vec::new();
+    for _ in 0..8 {
+        streams.push(TcpStream::connect(&mut client, SocketAddrV4::new(SERVER, 80)).await.unwrap());
+    }
+    for (i, stream) in streams.iter_mut().enumerate().rev() {
+        let message = format!("stream {}", i);
+        stream.write_all(message.as_bytes()).await.unwrap();
+        let mut buf = vec![0u8; message.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, message.as_bytes());
+        stream.shutdown().await.unwrap();
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), server_task).await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn closing_the_listener_wakes_accept() {
+    let (_client, mut server) = interfaces();
+    let listener = Arc::new(TcpListener::bind(&mut server, 80).unwrap());
+
+    let accepting = {
+        let listener = listener.clone();
+        tokio::spawn(async move { listener.accept().await.err().map(|e| e.kind()) })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    listener.close().unwrap();
+
+    let err = tokio::time::timeout(Duration::from_secs(1), accepting).await.unwrap().unwrap();
+    assert_eq!(err, Some(io::ErrorKind::ConnectionAborted));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn shutting_down_the_interface_wakes_readers() {
+    let (mut client, mut server) = interfaces();
+    let listener = TcpListener::bind(&mut server, 80).unwrap();
+    let accepting = tokio::spawn(async move { listener.accept().await.unwrap() });
+    let mut stream = TcpStream::connect(&mut client, SocketAddrV4::new(SERVER, 80)).await.unwrap();
+    let _accepted = accepting.await.unwrap();
+
+    let reading = tokio::spawn(async move { stream.read(&mut [0u8; 16]).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    tokio::task::spawn_blocking(move || client.shutdown(Teardown::Abort)).await.unwrap().unwrap();
+
+    let r = tokio::time::timeout(Duration::from_secs(1), reading).await.unwrap().unwrap();
+    assert!(r.is_err());
+}
@@ -0,0 +1,50 @@
+//! Fixtures shared by the integration tests. Each test crate uses only some of them.
+#![allow(dead_code)]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use trust::device;
+use trust::impair::Impairments;
+use trust::sim::Simulation;
+use trust::{Interface, InterfaceBuilder};
+
+pub const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+pub const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Interfaces at `CLIENT` and `SERVER`, on either end of an in-memory link
+pub fn interfaces() -> (Interface, Interface) {
+    let (a, b) = device::pipe(1500);
+    let client = InterfaceBuilder::new().address(CLIENT, 24).build_with(a).unwrap();
+    let server = InterfaceBuilder::new().address(SERVER, 24).build_with(b).unwrap();
+    (client, server)
+}
+
+/// A lossless simulated link between the stack built from `builder`, and a peer at `peer` that
+/// the test plays
+pub fn network(builder: InterfaceBuilder, peer: impl Into<IpAddr>) -> (Simulation, Interface) {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let iface = sim.add_host(builder).unwrap();
+    sim.add_peer(peer).unwrap();
+    (sim, iface)
+}
+
+/// The Internet checksum of `data` (RFC 1071), which comes out 0 if `data` holds the right one
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2).map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32).sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !sum as u16
+}
+
+/// The ICMPv6 or transport checksum of `data` from `src` to `dst`, over the IPv6 pseudo header
+/// (RFC 8200 S8.1)
+pub fn checksum6(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, data: &[u8]) -> u16 {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&src.octets());
+    bytes.extend_from_slice(&dst.octets());
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&[0, 0, 0, next_header]);
+    bytes.extend_from_slice(data);
+    checksum(&bytes)
+}
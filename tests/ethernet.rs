@@ -0,0 +1,226 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use trust::device::{self, Device, Pipe};
+use trust::{Interface, InterfaceBuilder};
+
+mod common;
+use common::checksum6;
+
+const STACK: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 254);
+const STACK6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+const PEER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+const STACK_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+const GATEWAY_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0xfe];
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+/// An interface at STACK on an Ethernet link, and the other end of the link for the test to
+/// play the rest of the segment
+fn segment(builder: InterfaceBuilder) -> (Interface, Pipe) {
+    let (a, b) = device::pipe(1514);
+    let iface = builder.address(STACK, 24).ethernet(STACK_MAC).build_with(a).unwrap();
+    (iface, b)
+}
+
+fn recv_frame(peer: &mut Pipe, timeout: Duration) -> Option<Vec<u8>> {
+    let mut buf = [0u8; 1514];
+    let n = peer.recv(&mut buf, Instant::now() + timeout).unwrap()?;
+    Some(buf[..n].to_vec())
+}
+
+/// The next frame of `ethertype` the stack sends
+fn recv_of(peer: &mut Pipe, ethertype: u16) -> Vec<u8> {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let frame = recv_frame(peer, timeout).expect("no frame from the stack");
+        if u16::from_be_bytes([frame[12], frame[13]]) == ethertype {
+            return frame;
+        }
+    }
+}
+
+fn frame(dst: [u8; 6], src: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn arp(op: u16, sha: [u8; 6], spa: Ipv4Addr, tha: [u8; 6], tpa: Ipv4Addr) -> Vec<u8> {
+    let mut packet = vec![0, 1, 8, 0, 6, 4];
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&sha);
+    packet.extend_from_slice(&spa.octets());
+    packet.extend_from_slice(&tha);
+    packet.extend_from_slice(&tpa.octets());
+    packet
+}
+
+/// The operation, sender and target IP address of an ARP frame
+fn read_arp(frame: &[u8]) -> (u16, Ipv4Addr, Ipv4Addr) {
+    let arp = &frame[14..];
+    let op = u16::from_be_bytes([arp[6], arp[7]]);
+    let spa = Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]);
+    let tpa = Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]);
+    (op, spa, tpa)
+}
+
+#[test]
+fn announces_itself_with_a_gratuitous_arp() {
+    let (_iface, mut peer) = segment(InterfaceBuilder::new());
+    let frame = recv_of(&mut peer, 0x0806);
+    assert_eq!(&frame[..12], &[BROADCAST, STACK_MAC].concat()[..]);
+    assert_eq!(read_arp(&frame), (1, STACK, STACK));
+}
+
+#[test]
+fn answers_arp_requests_for_its_address() {
+    let (_iface, mut peer) = segment(InterfaceBuilder::new());
+    recv_of(&mut peer, 0x0806);
+
+    // nobody asks the stack about other hosts
+    peer.send(&frame(BROADCAST, PEER_MAC, 0x0806, &arp(1, PEER_MAC, PEER, [0; 6], GATEWAY))).unwrap();
+    assert!(recv_frame(&mut peer, Duration::from_millis(100)).is_none());
+
+    peer.send(&frame(BROADCAST, PEER_MAC, 0x0806, &arp(1, PEER_MAC, PEER, [0; 6], STACK))).unwrap();
+    let reply = recv_of(&mut peer, 0x0806);
+    assert_eq!(&reply[..12], &[PEER_MAC, STACK_MAC].concat()[..]);
+    assert_eq!(read_arp(&reply), (2, STACK, PEER));
+    assert_eq!(&reply[14 + 8..14 + 14], &STACK_MAC);
+    assert_eq!(&reply[14 + 18..14 + 24], &PEER_MAC);
+}
+
+#[test]
+fn resolves_neighbors_before_sending_to_them() {
+    let (mut iface, mut peer) = segment(InterfaceBuilder::new());
+    recv_of(&mut peer, 0x0806);
+    let socket = iface.bind_udp(5000).unwrap();
+
+    socket.send_to(b"first", SocketAddr::from((PEER, 53))).unwrap();
+    let request = recv_of(&mut peer, 0x0806);
+    assert_eq!(&request[..6], &BROADCAST);
+    assert_eq!(read_arp(&request), (1, STACK, PEER));
+
+    peer.send(&frame(STACK_MAC, PEER_MAC, 0x0806, &arp(2, PEER_MAC, PEER, STACK_MAC, STACK))).unwrap();
+    let datagram = recv_of(&mut peer, 0x0800);
+    assert_eq!(&datagram[..12], &[PEER_MAC, STACK_MAC].concat()[..]);
+    assert_eq!(&datagram[14 + 28..14 + 33], b"first");
+
+    // now the stack knows where the peer is
+    socket.send_to(b"second", SocketAddr::from((PEER, 53))).unwrap();
+    let datagram = recv_frame(&mut peer, Duration::from_secs(1)).unwrap();
+    assert_eq!(u16::from_be_bytes([datagram[12], datagram[13]]), 0x0800);
+    assert_eq!(&datagram[14 + 28..14 + 34], b"second");
+}
+
+#[test]
+fn gives_up_on_neighbors_that_dont_answer() {
+    let (mut iface, mut peer) = segment(InterfaceBuilder::new());
+    recv_of(&mut peer, 0x0806);
+    let socket = iface.bind_udp(5000).unwrap();
+    socket.send_to(b"hello?", SocketAddr::from((PEER, 53))).unwrap();
+
+    let start = Instant::now();
+    let mut requests = Vec::new();
+    while let Some(frame) = recv_frame(&mut peer, Duration::from_millis(1500)) {
+        assert_eq!(read_arp(&frame), (1, STACK, PEER));
+        requests.push(start.elapsed());
+    }
+    assert_eq!(requests.len(), 3);
+    assert!(requests[2] - requests[1] >= Duration::from_millis(900));
+
+    // the datagram is gone, and an answer now doesn't bring it back
+    peer.send(&frame(STACK_MAC, PEER_MAC, 0x0806, &arp(2, PEER_MAC, PEER, STACK_MAC, STACK))).unwrap();
+    assert!(recv_frame(&mut peer, Duration::from_millis(100)).is_none());
+}
+
+#[test]
+fn ignores_frames_for_other_hosts() {
+    let (mut iface, mut peer) = segment(InterfaceBuilder::new());
+    let _listener = iface.bind(80).unwrap();
+    recv_of(&mut peer, 0x0806);
+
+    let mut syn = Vec::new();
+    etherparse::PacketBuilder::ipv4(PEER.octets(), STACK.octets(), 64)
+        .tcp(40000, 80, 0, 1024)
+        .syn()
+        .write(&mut syn, &[])
+        .unwrap();
+    peer.send(&frame([0x02, 0, 0, 0, 0, 3], PEER_MAC, 0x0800, &syn)).unwrap();
+    assert!(recv_frame(&mut peer, Duration::from_millis(100)).is_none());
+
+    peer.send(&frame(STACK_MAC, PEER_MAC, 0x0800, &syn)).unwrap();
+    let request = recv_of(&mut peer, 0x0806);
+    assert_eq!(read_arp(&request), (1, STACK, PEER));
+    peer.send(&frame(STACK_MAC, PEER_MAC, 0x0806, &arp(2, PEER_MAC, PEER, STACK_MAC, STACK))).unwrap();
+    let synack = recv_of(&mut peer, 0x0800);
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&synack[14 + 20..]).unwrap();
+    assert!(tcph.syn() && tcph.ack());
+}
+
+#[test]
+fn sends_through_the_gateway_outside_the_subnet() {
+    let (mut iface, mut peer) = segment(InterfaceBuilder::new().gateway(GATEWAY));
+    recv_of(&mut peer, 0x0806);
+    let socket = iface.bind_udp(5000).unwrap();
+
+    let far = Ipv4Addr::new(192, 0, 2, 1);
+    socket.send_to(b"far away", SocketAddr::from((far, 53))).unwrap();
+    let request = recv_of(&mut peer, 0x0806);
+    assert_eq!(read_arp(&request), (1, STACK, GATEWAY));
+
+    peer.send(&frame(STACK_MAC, GATEWAY_MAC, 0x0806, &arp(2, GATEWAY_MAC, GATEWAY, STACK_MAC, STACK))).unwrap();
+    let datagram = recv_of(&mut peer, 0x0800);
+    assert_eq!(&datagram[..6], &GATEWAY_MAC);
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(&datagram[14..]).unwrap();
+    assert_eq!(iph.destination_addr(), far);
+}
+
+#[test]
+fn answers_neighbor_solicitations() {
+    let (_iface, mut peer) = segment(InterfaceBuilder::new().address6(STACK6, 64));
+    recv_of(&mut peer, 0x0806);
+
+    // sent to the solicited-node group of the stack's address
+    let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 1, 0xff00, 2);
+    let mut message = vec![135, 0, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&STACK6.octets());
+    message.extend_from_slice(&[1, 1]);
+    message.extend_from_slice(&PEER_MAC);
+    let sum = checksum6(PEER6, group, 58, &message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    let ip = etherparse::Ipv6Header {
+        traffic_class: 0,
+        flow_label: 0,
+        payload_length: message.len() as u16,
+        next_header: 58,
+        hop_limit: 255,
+        source: PEER6.octets(),
+        destination: group.octets(),
+    };
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(&message);
+    peer.send(&frame([0x33, 0x33, 0xff, 0, 0, 2], PEER_MAC, 0x86dd, &packet)).unwrap();
+
+    // skip the stack's own unsolicited advertisement
+    let advertisement = loop {
+        let frame = recv_of(&mut peer, 0x86dd);
+        if frame[..6] == PEER_MAC {
+            break frame;
+        }
+    };
+    let (iph, message) = etherparse::Ipv6Header::read_from_slice(&advertisement[14..]).unwrap();
+    assert_eq!((iph.source, iph.destination, iph.hop_limit), (STACK6.octets(), PEER6.octets(), 255));
+    assert_eq!(&message[..2], &[136, 0]);
+    assert_eq!(message[4], 0x60);
+    assert_eq!(&message[8..24], &STACK6.octets());
+    assert_eq!(&message[24..32], &[&[2u8, 1][..], &STACK_MAC[..]].concat()[..]);
+    assert_eq!(checksum6(STACK6, PEER6, 58, message), 0);
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddrV4;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+mod common;
+use common::{interfaces, SERVER};
+
+/// Whether `fd` becomes readable within `timeout`
+fn readable(fd: RawFd, timeout: Duration) -> bool {
+    let mut pfd = [nix::poll::PollFd::new(fd, nix::poll::PollFlags::POLLIN)];
+    nix::poll::poll(&mut pfd, timeout.as_millis() as i32).unwrap() == 1
+}
+
+#[test]
+fn stream_fd_is_readable_until_a_read_would_block() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    let mut accepted = listener.accept().unwrap();
+    accepted.set_nonblocking(true).unwrap();
+    // the last ACK of the handshake changes what the stream is ready for, so let it land
+    std::thread::sleep(Duration::from_millis(50));
+
+    // a new descriptor asks for a first look
+    let fd = accepted.event_fd().unwrap();
+    assert_eq!(accepted.event_fd().unwrap(), fd);
+    assert!(readable(fd, Duration::ZERO));
+    let mut buf = [0u8; 16];
+    assert_eq!(accepted.read(&mut buf).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+    assert!(!readable(fd, Duration::from_millis(50)));
+
+    stream.write_all(b"hello").unwrap();
+    assert!(readable(fd, Duration::from_secs(1)));
+    assert_eq!(accepted.read(&mut buf).unwrap(), 5);
+    assert_eq!(accepted.read(&mut buf).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+    assert!(!readable(fd, Duration::from_millis(50)));
+}
+
+#[test]
+fn listener_fd_is_readable_when_a_connection_comes_in() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let fd = listener.event_fd().unwrap();
+    assert_eq!(listener.accept().err().unwrap().kind(), io::ErrorKind::WouldBlock);
+    assert!(!readable(fd, Duration::from_millis(50)));
+
+    let _stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    assert!(readable(fd, Duration::from_secs(1)));
+    assert!(listener.accept().is_ok());
+}
+
+#[cfg(feature = "mio")]
+#[test]
+fn streams_and_listeners_are_mio_sources() {
+    use mio::{Events, Interest, Poll, Token};
+
+    let (mut client, mut server) = interfaces();
+    let mut listener = server.bind(80).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let mut poll = Poll::new().unwrap();
+    poll.registry().register(&mut listener, Token(0), Interest::READABLE).unwrap();
+    let mut events = Events::with_capacity(8);
+
+    // nothing yet, once we saw that
+    poll.poll(&mut events, Some(Duration::from_millis(50))).unwrap();
+    assert_eq!(listener.accept().err().unwrap().kind(), io::ErrorKind::WouldBlock);
+    poll.poll(&mut events, Some(Duration::from_millis(50))).unwrap();
+    assert!(events.is_empty());
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(events.iter().map(|e| e.token()).collect::<Vec<_>>(), vec![Token(0)]);
+    let mut accepted = listener.accept().unwrap();
+    accepted.set_nonblocking(true).unwrap();
+    poll.registry().register(&mut accepted, Token(1), Interest::READABLE).unwrap();
+
+    let mut buf = [0u8; 16];
+    poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(accepted.read(&mut buf).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+    stream.write_all(b"hello").unwrap();
+    // the end of the handshake may come first, like events of kernel sockets can be spurious
+    let n = loop {
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        assert!(events.iter().any(|e| e.token() == Token(1) && e.is_readable()));
+        match accepted.read(&mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            r => break r.unwrap(),
+        }
+    };
+    assert_eq!(n, 5);
+
+    poll.registry().deregister(&mut accepted).unwrap();
+    poll.registry().deregister(&mut listener).unwrap();
+}
@@ -0,0 +1,166 @@
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use trust::sim::Simulation;
+use trust::{Interface, InterfaceBuilder};
+
+mod common;
+
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const STACK: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PEER_PORT: u16 = 40000;
+
+fn network() -> (Simulation, Interface) {
+    common::network(InterfaceBuilder::new().address(STACK, 24).recv_buffer_size(4096), PEER)
+}
+
+/// A segment from the peer to port 80, as one IP packet
+fn segment(seq: u32, ack: Option<u32>, syn: bool, payload: &[u8]) -> Vec<u8> {
+    let mut builder = etherparse::PacketBuilder::ipv4(PEER.octets(), STACK.octets(), 64)
+        .tcp(PEER_PORT, 80, seq, 65535);
+    if syn {
+        builder = builder.syn();
+    }
+    if let Some(ack) = ack {
+        builder = builder.ack(ack);
+    }
+    let mut packet = Vec::new();
+    builder.write(&mut packet, payload).unwrap();
+    packet
+}
+
+/// Split the payload of `packet` into fragments covering the byte ranges `pieces`
+fn fragments(packet: &[u8], id: u16, pieces: &[(usize, usize)]) -> Vec<Vec<u8>> {
+    let (header, payload) = etherparse::Ipv4Header::read_from_slice(packet).unwrap();
+    pieces
+        .iter()
+        .map(|&(start, end)| {
+            let mut h = header.clone();
+            h.payload_len = (end - start) as u16;
+            h.identification = id;
+            h.fragments_offset = (start / 8) as u16;
+            h.more_fragments = end < payload.len();
+            let mut fragment = Vec::new();
+            h.write(&mut fragment).unwrap();
+            fragment.extend_from_slice(&payload[start..end]);
+            fragment
+        })
+        .collect()
+}
+
+fn received_tcp(sim: &mut Simulation) -> Vec<etherparse::TcpHeader> {
+    sim.take_received()
+        .into_iter()
+        .map(|(_, packet)| {
+            let (_, rest) = etherparse::Ipv4Header::read_from_slice(&packet).unwrap();
+            etherparse::TcpHeader::read_from_slice(rest).unwrap().0
+        })
+        .collect()
+}
+
+#[test]
+fn fragmented_syn_is_answered() {
+    let (mut sim, mut iface) = network();
+    let _listener = iface.bind(80).unwrap();
+
+    // the last fragment first
+    for f in fragments(&segment(0, None, true, &[]), 1, &[(8, 20), (0, 8)]) {
+        sim.send(f);
+    }
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    let replies = received_tcp(&mut sim);
+    assert_eq!(replies.len(), 1);
+    assert!(replies[0].syn && replies[0].ack);
+    assert_eq!(replies[0].acknowledgment_number, 1);
+    assert_eq!(iface.stats().reassembly_failures, 0);
+}
+
+#[test]
+fn fragmented_data_is_read_in_order() {
+    let (mut sim, mut iface) = network();
+    let listener = iface.bind(80).unwrap();
+
+    sim.send(segment(0, None, true, &[]));
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    let isn = received_tcp(&mut sim)[0].sequence_number;
+    sim.send(segment(1, Some(isn.wrapping_add(1)), false, &[]));
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    let mut stream = listener.accept().unwrap();
+
+    // out of order, with one fragment sent twice
+    let data: Vec<u8> = (0..1200u32).map(|i| i as u8).collect();
+    let packet = segment(1, Some(isn.wrapping_add(1)), false, &data);
+    let mut pieces = fragments(&packet, 2, &[(0, 400), (400, 800), (800, 1220)]);
+    pieces.swap(0, 2);
+    pieces.push(pieces[1].clone());
+    for f in pieces {
+        sim.send(f);
+    }
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let mut buf = vec![0u8; 2000];
+    let n = stream.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &data[..]);
+    assert_eq!(iface.stats().reassembly_failures, 0);
+}
+
+#[test]
+fn fragment_sent_again_before_the_datagram_is_complete_is_ignored() {
+    let (mut sim, mut iface) = network();
+    let listener = iface.bind(80).unwrap();
+
+    sim.send(segment(0, None, true, &[]));
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    let isn = received_tcp(&mut sim)[0].sequence_number;
+    sim.send(segment(1, Some(isn.wrapping_add(1)), false, &[]));
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    let mut stream = listener.accept().unwrap();
+
+    // the first fragment comes again after it merged with the second
+    let data: Vec<u8> = (0..1200u32).map(|i| i as u8).collect();
+    let packet = segment(1, Some(isn.wrapping_add(1)), false, &data);
+    let pieces = fragments(&packet, 2, &[(0, 400), (400, 800), (800, 1220)]);
+    for i in [0, 1, 0, 2] {
+        sim.send(pieces[i].clone());
+    }
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let mut buf = vec![0u8; 2000];
+    let n = stream.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &data[..]);
+    assert_eq!(iface.stats().reassembly_failures, 0);
+}
+
+#[test]
+fn conflicting_fragments_drop_the_datagram() {
+    let (mut sim, mut iface) = network();
+    let _listener = iface.bind(80).unwrap();
+
+    let packet = segment(0, None, true, &[0; 16]);
+    let mut pieces = fragments(&packet, 3, &[(0, 16), (8, 36)]);
+    // the overlapping bytes say something else the second time
+    pieces[1][20] ^= 0xff;
+    for f in pieces {
+        sim.send(f);
+    }
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    assert!(received_tcp(&mut sim).is_empty());
+    assert_eq!(iface.stats().reassembly_failures, 1);
+}
+
+#[test]
+fn incomplete_datagrams_time_out() {
+    let (mut sim, mut iface) = network();
+    let _listener = iface.bind(80).unwrap();
+
+    let pieces = fragments(&segment(0, None, true, &[]), 4, &[(0, 8), (8, 20)]);
+    sim.send(pieces[0].clone());
+    sim.run_for(Duration::from_secs(31)).unwrap();
+    assert_eq!(iface.stats().reassembly_failures, 1);
+
+    // the rest arriving late can't complete what was given up on
+    sim.send(pieces[1].clone());
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    assert!(received_tcp(&mut sim).is_empty());
+}
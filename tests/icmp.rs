@@ -0,0 +1,128 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use trust::sim::Simulation;
+use trust::{Interface, InterfaceBuilder, TcpStream};
+
+mod common;
+use common::checksum;
+
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const STACK: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const ROUTER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 254);
+
+fn network() -> (Simulation, Interface) {
+    common::network(InterfaceBuilder::new().address(STACK, 24), PEER)
+}
+
+/// An ICMP message from `src` to the stack
+fn icmp(src: Ipv4Addr, kind: u8, code: u8, rest: [u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut message = vec![kind, code, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(body);
+    let sum = checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let ip = etherparse::Ipv4Header::new(message.len() as u16, 64, etherparse::IpTrafficClass::Icmp, src.octets(), STACK.octets());
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(&message);
+    packet
+}
+
+/// A router's error about `packet`, quoting its IP header and 8 bytes after it
+fn error_about(packet: &[u8], kind: u8, code: u8) -> Vec<u8> {
+    icmp(ROUTER, kind, code, [0; 4], &packet[..28])
+}
+
+/// Connect to the peer, and return the stream with the SYN the stack sent
+fn connecting(sim: &mut Simulation, iface: &mut Interface) -> (TcpStream, Vec<u8>) {
+    let stream = iface.connect(SocketAddrV4::new(PEER, 80)).unwrap();
+    sim.run_for(Duration::from_millis(20)).unwrap();
+    let (_, syn) = sim.take_received().remove(0);
+    (stream, syn)
+}
+
+/// Run until reading `stream` fails with something other than `WouldBlock`.
+fn read_error(sim: &mut Simulation, stream: &mut TcpStream, limit: Duration) -> Option<io::ErrorKind> {
+    let mut err = None;
+    sim.run_until(limit, || match stream.read(&mut [0u8; 1]) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+        r => {
+            err = r.err().map(|e| e.kind());
+            true
+        }
+    }).unwrap();
+    err
+}
+
+#[test]
+fn pings_are_answered() {
+    let (mut sim, _iface) = network();
+    sim.send(icmp(PEER, 8, 0, [0x12, 0x34, 0, 7], b"are you there?"));
+    sim.run_for(Duration::from_millis(20)).unwrap();
+
+    let replies = sim.take_received();
+    assert_eq!(replies.len(), 1);
+    let (iph, message) = etherparse::Ipv4Header::read_from_slice(&replies[0].1).unwrap();
+    assert_eq!((iph.source, iph.destination, iph.protocol), (STACK.octets(), PEER.octets(), 1));
+    assert_eq!(&message[..2], &[0, 0]);
+    assert_eq!(&message[4..], b"\x12\x34\x00\x07are you there?");
+    assert_eq!(checksum(message), 0);
+}
+
+#[test]
+fn port_unreachable_aborts_the_handshake() {
+    let (mut sim, mut iface) = network();
+    let (mut stream, syn) = connecting(&mut sim, &mut iface);
+
+    sim.send(error_about(&syn, 3, 3));
+    let err = read_error(&mut sim, &mut stream, Duration::from_millis(100));
+    assert_eq!(err, Some(io::ErrorKind::ConnectionRefused));
+}
+
+#[test]
+fn host_unreachable_is_reported_when_the_handshake_times_out() {
+    let (mut sim, mut iface) = network();
+    let (mut stream, syn) = connecting(&mut sim, &mut iface);
+
+    sim.send(error_about(&syn, 3, 1));
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    // the stack keeps trying
+    assert!(sim.take_received().iter().any(|(_, p)| p.len() == syn.len()));
+
+    let err = read_error(&mut sim, &mut stream, Duration::from_secs(3600));
+    assert_eq!(err, Some(io::ErrorKind::HostUnreachable));
+}
+
+#[test]
+fn errors_about_established_connections_are_soft() {
+    let (mut sim, mut iface) = network();
+    let (mut stream, syn) = connecting(&mut sim, &mut iface);
+
+    // the peer answers the SYN
+    let isn = u32::from_be_bytes([syn[24], syn[25], syn[26], syn[27]]);
+    let mut synack = Vec::new();
+    etherparse::PacketBuilder::ipv4(PEER.octets(), STACK.octets(), 64)
+        .tcp(80, u16::from_be_bytes([syn[20], syn[21]]), 1000, 65535)
+        .syn()
+        .ack(isn.wrapping_add(1))
+        .write(&mut synack, &[])
+        .unwrap();
+    sim.send(synack);
+    sim.run_for(Duration::from_millis(20)).unwrap();
+    sim.take_received();
+
+    stream.write_all(b"hello").unwrap();
+    sim.run_for(Duration::from_millis(20)).unwrap();
+    let (_, data) = sim.take_received().remove(0);
+    sim.send(error_about(&data, 3, 3));
+    sim.send(error_about(&data, 11, 0));
+    sim.run_for(Duration::from_millis(20)).unwrap();
+
+    let err = stream.read(&mut [0u8; 1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    stream.write_all(b" again").unwrap();
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use trust::device::{self, Device};
+use trust::impair::{Distribution, Impaired, Impairments};
+use trust::InterfaceBuilder;
+
+fn impairments(spec: &str) -> Impairments {
+    spec.parse().unwrap()
+}
+
+/// Send `n` numbered packets through `imp` and collect what comes out within `wait`
+fn through(imp: Impairments, n: u8, wait: Duration) -> Vec<Vec<u8>> {
+    let (a, mut b) = device::pipe(1500);
+    let mut a = Impaired::new(a, imp, Impairments::default());
+    for i in 0..n {
+        a.send(&[i; 100]).unwrap();
+    }
+
+    let mut out = Vec::new();
+    let mut buf = [0u8; 1500];
+    let deadline = Instant::now() + wait;
+    while Instant::now() < deadline {
+        // the impaired end sends delayed packets while it waits for incoming ones
+        a.recv(&mut buf, Instant::now() + Duration::from_millis(1)).unwrap();
+        while let Some(n) = b.recv(&mut buf, Instant::now()).unwrap() {
+            out.push(buf[..n].to_vec());
+        }
+    }
+    out
+}
+
+#[test]
+fn parses_netem_syntax() {
+    let imp = impairments("delay 20ms 5ms distribution normal loss 1% duplicate 0.5% corrupt 0.1% reorder 25% rate 1mbit limit 50 seed 7");
+    assert_eq!(imp.delay, Duration::from_millis(20));
+    assert_eq!(imp.jitter, Duration::from_millis(5));
+    assert_eq!(imp.distribution, Distribution::Normal);
+    assert_eq!(imp.loss, 0.01);
+    assert_eq!(imp.duplicate, 0.005);
+    assert_eq!(imp.corrupt, 0.001);
+    assert_eq!(imp.reorder, 0.25);
+    assert_eq!(imp.rate, Some(1_000_000));
+    assert_eq!(imp.limit, 50);
+    assert_eq!(imp.seed, Some(7));
+
+    for bad in &["loss", "loss 120%", "delay soon", "rate 5 parsecs", "jitter 5ms"] {
+        assert_eq!(bad.parse::<Impairments>().unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", bad);
+    }
+}
+
+#[test]
+fn loss_and_duplication() {
+    assert!(through(impairments("loss 100%"), 10, Duration::from_millis(20)).is_empty());
+    assert_eq!(through(impairments("duplicate 100%"), 10, Duration::from_millis(20)).len(), 20);
+
+    let out = through(impairments("loss 50% seed 1"), 100, Duration::from_millis(20));
+    assert!(out.len() > 20 && out.len() < 80, "{} packets got through", out.len());
+}
+
+#[test]
+fn corruption_flips_one_bit() {
+    let out = through(impairments("corrupt 100% seed 3"), 10, Duration::from_millis(20));
+    assert_eq!(out.len(), 10);
+    for packet in out {
+        // every byte was the same before
+        let original = if packet[0] == packet[1] { packet[0] } else { packet[2] };
+        let flipped: u32 = packet.iter().map(|b| (b ^ original).count_ones()).sum();
+        assert_eq!(flipped, 1);
+    }
+}
+
+#[test]
+fn delay_and_reordering() {
+    let start = Instant::now();
+    let out = through(impairments("delay 30ms"), 5, Duration::from_millis(60));
+    assert_eq!(out.len(), 5);
+    assert!(start.elapsed() >= Duration::from_millis(30));
+    assert!(out.windows(2).all(|w| w[0][0] < w[1][0]), "a constant delay keeps the order");
+
+    let out = through(impairments("delay 30ms reorder 50% seed 5"), 20, Duration::from_millis(60));
+    assert_eq!(out.len(), 20);
+    assert!(out.windows(2).any(|w| w[0][0] > w[1][0]), "nothing was reordered");
+}
+
+#[test]
+fn rate_limit_spaces_packets_out() {
+    // 100 byte packets at 80 kbit/s take 10ms each
+    let start = Instant::now();
+    let out = through(impairments("rate 80kbit"), 5, Duration::from_millis(100));
+    assert_eq!(out.len(), 5);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // and packets beyond the queue limit are dropped
+    assert_eq!(through(impairments("rate 80kbit limit 3"), 5, Duration::from_millis(100)).len(), 3);
+}
+
+#[test]
+fn transfer_over_impaired_loopback() {
+    let (a, b) = device::pipe(1500);
+    let lossy = impairments("delay 2ms 1ms loss 5% duplicate 5% reorder 10% seed 11");
+    let mut client = InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 1), 24).impair(lossy).build_with(a).unwrap();
+    let mut server = InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 2), 24).impair(lossy).build_with(b).unwrap();
+    let listener = server.bind(80).unwrap();
+    let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+
+    let expected = data.clone();
+    let jh = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        assert_eq!(received, expected);
+        server
+    });
+
+    let mut stream = client.connect(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80)).unwrap();
+    let mut sent = 0;
+    while sent < data.len() {
+        match stream.write(&data[sent..]) {
+            Ok(n) => sent += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(e) => panic!("write failed: {}", e),
+        }
+    }
+    drop(stream);
+    jh.join().unwrap();
+}
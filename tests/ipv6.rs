@@ -0,0 +1,257 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use trust::impair::Impairments;
+use trust::sim::Simulation;
+use trust::{Interface, InterfaceBuilder, TcpStream};
+
+mod common;
+use common::checksum6;
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const CLIENT6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+const SERVER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+const PEER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3);
+const ROUTER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0xfe);
+const PEER_PORT: u16 = 40000;
+
+fn would_block<T>(r: io::Result<T>) -> Option<T> {
+    match r {
+        Ok(t) => Some(t),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+/// The stack at `SERVER6`, and a peer at `PEER6` the test plays
+fn network() -> (Simulation, Interface) {
+    let builder = InterfaceBuilder::new().address(SERVER, 24).address6(SERVER6, 64).send_buffer_size(64 * 1024);
+    common::network(builder, PEER6)
+}
+
+/// An IPv6 packet from `src` to the stack with `payload` behind `next_header`
+fn ipv6(src: Ipv6Addr, next_header: u8, payload: &[u8]) -> Vec<u8> {
+    let ip = etherparse::Ipv6Header {
+        traffic_class: 0,
+        flow_label: 0,
+        payload_length: payload.len() as u16,
+        next_header,
+        hop_limit: 64,
+        source: src.octets(),
+        destination: SERVER6.octets(),
+    };
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// An ICMPv6 message from `src` to the stack
+fn icmp6(src: Ipv6Addr, kind: u8, code: u8, rest: [u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut message = vec![kind, code, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(body);
+    let sum = checksum6(src, SERVER6, 58, &message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    ipv6(src, 58, &message)
+}
+
+fn segment(seq: u32, ack: Option<u32>, syn: bool) -> Vec<u8> {
+    let mut builder = etherparse::PacketBuilder::ipv6(PEER6.octets(), SERVER6.octets(), 64)
+        .tcp(PEER_PORT, 80, seq, 65535);
+    if syn {
+        builder = builder.syn().options(&[etherparse::TcpOptionElement::MaximumSegmentSize(1440)]).unwrap();
+    }
+    if let Some(ack) = ack {
+        builder = builder.ack(ack);
+    }
+    let mut packet = Vec::new();
+    builder.write(&mut packet, &[]).unwrap();
+    packet
+}
+
+fn tcp(packet: &[u8]) -> etherparse::TcpHeader {
+    let (iph, rest) = etherparse::Ipv6Header::read_from_slice(packet).unwrap();
+    assert_eq!(iph.next_header, 6);
+    etherparse::TcpHeader::read_from_slice(rest).unwrap().0
+}
+
+/// A connection from the peer to the stack, with the stack's ISN
+fn connected(sim: &mut Simulation, iface: &mut Interface) -> (TcpStream, u32) {
+    let listener = iface.bind(80).unwrap();
+    sim.send(segment(0, None, true));
+    sim.run_for(Duration::from_millis(20)).unwrap();
+    let (_, synack) = sim.take_received().remove(0);
+    let isn = tcp(&synack).sequence_number;
+    sim.send(segment(1, Some(isn.wrapping_add(1)), false));
+    sim.run_for(Duration::from_millis(20)).unwrap();
+    (listener.accept().unwrap(), isn)
+}
+
+#[test]
+fn listeners_accept_both_families() {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let mut client = sim.add_host(InterfaceBuilder::new().address(CLIENT, 24).address6(CLIENT6, 64)).unwrap();
+    let mut server = sim.add_host(InterfaceBuilder::new().address(SERVER, 24).address6(SERVER6, 64)).unwrap();
+    let listener = server.bind(80).unwrap();
+
+    let mut streams = vec![
+        client.connect(SocketAddr::from((SERVER6, 80))).unwrap(),
+        client.connect(SocketAddr::from((SERVER, 80))).unwrap(),
+    ];
+    let mut accepted = Vec::new();
+    let mut written = 0;
+    let mut received = Vec::new();
+    let done = sim.run_until(Duration::from_secs(10), || {
+        while written < streams.len() {
+            let msg = format!("hello {}", written);
+            match would_block(streams[written].write(msg.as_bytes())) {
+                Some(_) => written += 1,
+                None => break,
+            }
+        }
+        if let Some(s) = would_block(listener.accept()) {
+            accepted.push(s);
+        }
+        for s in &mut accepted {
+            let mut buf = [0u8; 64];
+            if let Some(n) = would_block(s.read(&mut buf)) {
+                received.push(String::from_utf8(buf[..n].to_vec()).unwrap());
+            }
+        }
+        received.len() == 2
+    }).unwrap();
+    assert!(done, "connections did not come through");
+    received.sort();
+    assert_eq!(received, ["hello 0", "hello 1"]);
+    streams.clear();
+
+    // both families went over the link
+    let versions: Vec<u8> = sim.trace().iter().map(|(_, p)| p[0] >> 4).collect();
+    assert!(versions.contains(&4) && versions.contains(&6));
+}
+
+#[test]
+fn the_syn_announces_an_mss_that_leaves_room_for_the_ipv6_header() {
+    let (mut sim, mut iface) = network();
+    let _stream = iface.connect(SocketAddr::from((PEER6, 80))).unwrap();
+    sim.run_for(Duration::from_millis(20)).unwrap();
+
+    let (_, syn) = sim.take_received().remove(0);
+    let (iph, segment) = etherparse::Ipv6Header::read_from_slice(&syn).unwrap();
+    assert_eq!((iph.source, iph.destination, iph.next_header), (SERVER6.octets(), PEER6.octets(), 6));
+    assert_eq!(checksum6(SERVER6, PEER6, 6, segment), 0);
+    let tcph = tcp(&syn);
+    assert!(tcph.syn);
+    let mss = tcph.options_iterator().find_map(|o| match o {
+        Ok(etherparse::TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+        _ => None,
+    });
+    assert_eq!(mss, Some(1440));
+}
+
+#[test]
+fn ipv4_only_interfaces_cant_reach_ipv6() {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let mut iface = sim.add_host(InterfaceBuilder::new().address(SERVER, 24)).unwrap();
+    let err = iface.connect(SocketAddr::from((PEER6, 80))).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+
+    let too_small = sim.add_host(InterfaceBuilder::new().address(CLIENT, 24).address6(CLIENT6, 64).mtu(1000));
+    assert_eq!(too_small.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn pings_are_answered() {
+    let (mut sim, _iface) = network();
+    sim.send(icmp6(PEER6, 128, 0, [0x12, 0x34, 0, 7], b"are you there?"));
+    sim.run_for(Duration::from_millis(20)).unwrap();
+
+    let replies = sim.take_received();
+    assert_eq!(replies.len(), 1);
+    let (iph, message) = etherparse::Ipv6Header::read_from_slice(&replies[0].1).unwrap();
+    assert_eq!((iph.source, iph.destination, iph.next_header), (SERVER6.octets(), PEER6.octets(), 58));
+    assert_eq!(&message[..2], &[129, 0]);
+    assert_eq!(&message[4..], b"\x12\x34\x00\x07are you there?");
+    assert_eq!(checksum6(SERVER6, PEER6, 58, message), 0);
+}
+
+#[test]
+fn extension_headers_are_skipped() {
+    let (mut sim, mut iface) = network();
+    let _listener = iface.bind(80).unwrap();
+
+    // the SYN behind a hop-by-hop header holding nothing but padding
+    let syn = segment(0, None, true);
+    let mut payload = vec![6, 0, 1, 4, 0, 0, 0, 0];
+    payload.extend_from_slice(&syn[40..]);
+    sim.send(ipv6(PEER6, 0, &payload));
+    sim.run_for(Duration::from_millis(20)).unwrap();
+
+    let (_, synack) = sim.take_received().remove(0);
+    let tcph = tcp(&synack);
+    assert!(tcph.syn && tcph.ack);
+    assert_eq!(tcph.acknowledgment_number, 1);
+}
+
+#[test]
+fn packet_too_big_shrinks_segments() {
+    let (mut sim, mut iface) = network();
+    let (mut stream, _) = connected(&mut sim, &mut iface);
+
+    stream.write_all(&[7u8; 4000]).unwrap();
+    sim.run_for(Duration::from_millis(20)).unwrap();
+    let sent = sim.take_received();
+    assert_eq!(sent[0].1.len(), 1500);
+
+    // quote as much as fits into the minimum MTU
+    sim.send(icmp6(ROUTER6, 2, 0, 1280u32.to_be_bytes(), &sent[0].1[..1232]));
+    sim.run_for(Duration::from_millis(20)).unwrap();
+    let resent = sim.take_received();
+    assert!(!resent.is_empty());
+    assert!(resent.iter().all(|(_, p)| p.len() <= 1280));
+    assert_eq!(resent[0].1.len(), 1280);
+}
+
+#[test]
+fn udp_goes_both_ways() {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let mut client = sim.add_host(InterfaceBuilder::new().address(CLIENT, 24).address6(CLIENT6, 64)).unwrap();
+    let mut server = sim.add_host(InterfaceBuilder::new().address(SERVER, 24).address6(SERVER6, 64)).unwrap();
+    let a = client.bind_udp(5000).unwrap();
+    let b = server.bind_udp(53).unwrap();
+
+    a.send_to(b"query", SocketAddr::from((SERVER6, 53))).unwrap();
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    let mut buf = [0u8; 64];
+    let (n, from) = b.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..n], from), (&b"query"[..], SocketAddr::from((CLIENT6, 5000))));
+
+    b.send_to(b"answer", from).unwrap();
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    let (n, from) = a.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..n], from), (&b"answer"[..], SocketAddr::from((SERVER6, 53))));
+}
+
+#[test]
+fn unbound_udp_ports_are_unreachable() {
+    let (mut sim, _iface) = network();
+    let mut datagram = Vec::new();
+    etherparse::PacketBuilder::ipv6(PEER6.octets(), SERVER6.octets(), 64)
+        .udp(4000, 53)
+        .write(&mut datagram, b"anyone?")
+        .unwrap();
+    sim.send(datagram.clone());
+    sim.run_for(Duration::from_millis(20)).unwrap();
+
+    let received = sim.take_received();
+    assert_eq!(received.len(), 1);
+    let (iph, message) = etherparse::Ipv6Header::read_from_slice(&received[0].1).unwrap();
+    assert_eq!((iph.next_header, iph.destination), (58, PEER6.octets()));
+    assert_eq!(&message[..2], &[1, 4]);
+    assert_eq!(&message[8..], &datagram[..]);
+    assert_eq!(checksum6(SERVER6, PEER6, 58, message), 0);
+}
@@ -0,0 +1,492 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use trust::device::{self, Device, Pipe};
+use trust::{Interface, InterfaceBuilder, Stats, Teardown};
+
+mod common;
+use common::{interfaces, CLIENT, SERVER};
+
+/// An interface at SERVER, and the other end of its link for the test to play the client
+fn scripted() -> (Interface, Pipe) {
+    let (a, b) = device::pipe(1500);
+    let server = InterfaceBuilder::new().address(SERVER, 24).build_with(a).unwrap();
+    (server, b)
+}
+
+fn send_segment(peer: &mut Pipe, seg: etherparse::PacketBuilderStep<etherparse::TcpHeader>) {
+    let mut buf = Vec::new();
+    seg.write(&mut buf, &[]).unwrap();
+    peer.send(&buf).unwrap();
+}
+
+fn recv_segment(peer: &mut Pipe) -> etherparse::TcpHeader {
+    let mut buf = [0u8; 1500];
+    let n = peer.recv(&mut buf, Instant::now() + Duration::from_secs(1)).unwrap().expect("no segment from the stack");
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(&buf[..n]).unwrap();
+    assert_eq!(iph.source_addr(), SERVER);
+    assert_eq!(iph.destination_addr(), CLIENT);
+    etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..n]).unwrap().to_header()
+}
+
+fn syn(port: u16) -> etherparse::PacketBuilderStep<etherparse::TcpHeader> {
+    etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+        .tcp(40000, port, 1000, 1024)
+        .syn()
+}
+
+#[test]
+fn connect_accept_read_write_close() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+
+    let jh = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"world").unwrap();
+
+        // the client shuts down its side after our reply
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        drop(stream);
+        server
+    });
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"world");
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    // and the server closes its side once it saw our FIN
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    let server = jh.join().unwrap();
+
+    client.shutdown(Teardown::Graceful(Duration::from_secs(1))).unwrap();
+    server.shutdown(Teardown::Graceful(Duration::from_secs(1))).unwrap();
+}
+
+#[test]
+fn transfer_larger_than_buffers() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+    let data: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+
+    let expected = data.clone();
+    let jh = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        assert_eq!(received, expected);
+        server
+    });
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    let mut sent = 0;
+    while sent < data.len() {
+        match stream.write(&data[sent..]) {
+            Ok(n) => sent += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(e) => panic!("write failed: {}", e),
+        }
+    }
+    drop(stream);
+
+    jh.join().unwrap();
+}
+
+/// A connected pair of streams, client first
+fn streams(client: &mut Interface, server: &mut Interface) -> (trust::TcpStream, trust::TcpStream) {
+    let listener = server.bind(80).unwrap();
+    let stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    (stream, listener.accept().unwrap())
+}
+
+#[test]
+fn reads_of_any_size_get_the_data_in_order() {
+    let (mut client, mut server) = interfaces();
+    let (mut stream, mut accepted) = streams(&mut client, &mut server);
+    let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+
+    let expected = data.clone();
+    let writer = thread::spawn(move || stream.write_all(&data).unwrap());
+    let mut received = Vec::new();
+    let mut buf = [0u8; 1500];
+    // the receive buffer wraps around somewhere else every time
+    for &size in [1, 7, 300, 1500].iter().cycle() {
+        let n = accepted.read(&mut buf[..size]).unwrap();
+        received.extend_from_slice(&buf[..n]);
+        if received.len() == expected.len() {
+            break;
+        }
+    }
+    assert_eq!(received, expected);
+    writer.join().unwrap();
+}
+
+#[test]
+fn vectored_reads_fill_the_buffers_in_order() {
+    let (mut client, mut server) = interfaces();
+    let (mut stream, mut accepted) = streams(&mut client, &mut server);
+    let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+
+    let expected = data.clone();
+    let writer = thread::spawn(move || stream.write_all(&data).unwrap());
+    let mut received = Vec::new();
+    while received.len() < expected.len() {
+        let (mut a, mut b, mut c) = ([0u8; 5], [0u8; 600], [0u8; 2000]);
+        let n = accepted.read_vectored(&mut [
+            io::IoSliceMut::new(&mut a),
+            io::IoSliceMut::new(&mut b),
+            io::IoSliceMut::new(&mut c),
+        ]).unwrap();
+        let all: Vec<u8> = a.iter().chain(&b).chain(&c).copied().collect();
+        received.extend_from_slice(&all[..n]);
+    }
+    assert_eq!(received, expected);
+    writer.join().unwrap();
+}
+
+#[test]
+fn peek_leaves_the_data_for_read() {
+    let (mut client, mut server) = interfaces();
+    let (mut stream, mut accepted) = streams(&mut client, &mut server);
+    stream.write_all(b"hello world").unwrap();
+
+    let mut buf = [0u8; 5];
+    assert_eq!(accepted.peek(&mut buf).unwrap(), 5);
+    assert_eq!(&buf, b"hello");
+    assert_eq!(accepted.peek(&mut buf).unwrap(), 5);
+    assert_eq!(&buf, b"hello");
+
+    let mut buf = [0u8; 11];
+    assert_eq!(accepted.read(&mut buf).unwrap(), 11);
+    assert_eq!(&buf, b"hello world");
+}
+
+#[test]
+fn read_with_lends_the_received_data() {
+    let (mut client, mut server) = interfaces();
+    let (mut stream, mut accepted) = streams(&mut client, &mut server);
+    let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+
+    let expected = data.clone();
+    let writer = thread::spawn(move || stream.write_all(&data).unwrap());
+    let mut received = Vec::new();
+    loop {
+        let n = accepted.read_with(|chunk| {
+            assert!(!chunk.is_empty());
+            // leave some for next time
+            let n = std::cmp::min(chunk.len(), 100);
+            received.extend_from_slice(&chunk[..n]);
+            n
+        }).unwrap();
+        if n == 0 {
+            break;
+        }
+    }
+    assert_eq!(received, expected);
+    writer.join().unwrap();
+    assert_eq!(accepted.read_with(|_| unreachable!("nothing left to lend")).unwrap(), 0);
+}
+
+#[test]
+fn read_with_consumes_no_more_than_it_lends() {
+    let (mut client, mut server) = interfaces();
+    let (mut stream, mut accepted) = streams(&mut client, &mut server);
+    let data: Vec<u8> = (0..1900u32).map(|i| (i % 251) as u8).collect();
+    let mut received = Vec::new();
+
+    // wait until all that was sent is there
+    let buffered = |accepted: &trust::TcpStream, n: usize| {
+        while accepted.peek(&mut [0u8; 2048]).unwrap() < n {
+            thread::sleep(Duration::from_millis(1));
+        }
+    };
+
+    // leave a little at the end of the receive buffer, so the next data wraps around
+    stream.write_all(&data[..1000]).unwrap();
+    buffered(&accepted, 1000);
+    accepted.read_with(|chunk| {
+        received.extend_from_slice(&chunk[..900]);
+        900
+    }).unwrap();
+    stream.write_all(&data[1000..]).unwrap();
+    buffered(&accepted, 1000);
+
+    // claiming more than it got must not drop what it didn't see
+    accepted.set_nonblocking(true).unwrap();
+    loop {
+        let r = accepted.read_with(|chunk| {
+            received.extend_from_slice(chunk);
+            usize::MAX
+        });
+        match r {
+            Ok(n) => assert!(n > 0),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    assert_eq!(received, data);
+}
+
+#[test]
+fn streams_transfer_concurrently() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+    let mut threads = Vec::new();
+    for i in 0..16u8 {
+        let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+        let mut accepted = listener.accept().unwrap();
+        threads.push(thread::spawn(move || stream.write_all(&[i; 8192]).unwrap()));
+        threads.push(thread::spawn(move || {
+            let mut received = vec![0u8; 8192];
+            accepted.read_exact(&mut received).unwrap();
+            assert!(received.iter().all(|&b| b == i));
+        }));
+    }
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn connect_to_closed_port_is_refused() {
+    let (mut client, _server) = interfaces();
+    let err = client.connect(SocketAddrV4::new(SERVER, 81)).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn syn_to_closed_port_is_reset() {
+    let (_server, mut peer) = scripted();
+    send_segment(&mut peer, syn(81));
+
+    let rst = recv_segment(&mut peer);
+    assert!(rst.rst && rst.ack);
+    assert_eq!(rst.acknowledgment_number, 1001);
+}
+
+#[test]
+fn closing_listener_resets_pending_connections() {
+    let (mut server, mut peer) = scripted();
+    let listener = server.bind(80).unwrap();
+
+    send_segment(&mut peer, syn(80));
+    let synack = recv_segment(&mut peer);
+    assert!(synack.syn && synack.ack);
+    assert_eq!(synack.acknowledgment_number, 1001);
+
+    drop(listener);
+    let rst = recv_segment(&mut peer);
+    assert!(rst.rst);
+    assert_eq!(rst.sequence_number, synack.sequence_number.wrapping_add(1));
+
+    // and the port stays closed
+    send_segment(&mut peer, syn(80));
+    assert!(recv_segment(&mut peer).rst);
+}
+
+#[test]
+fn accept_fails_once_listener_is_closed() {
+    let (_client, mut server) = interfaces();
+    let listener = std::sync::Arc::new(server.bind(80).unwrap());
+
+    let l = listener.clone();
+    let jh = thread::spawn(move || l.accept().err().unwrap().kind());
+    thread::sleep(Duration::from_millis(50));
+    listener.close().unwrap();
+    assert_eq!(jh.join().unwrap(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn shutdown_closes_open_connections() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    let _accepted = listener.accept().unwrap();
+    let jh = thread::spawn(move || {
+        // dropping the stream answers the server's FIN with ours
+        stream.read(&mut [0u8; 1]).unwrap()
+    });
+
+    let start = Instant::now();
+    server.shutdown(Teardown::Graceful(Duration::from_secs(5))).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5), "close did not finish before the deadline");
+    assert_eq!(jh.join().unwrap(), 0);
+}
+
+/// Build the packet of `seg` and flip a bit at `offset`
+fn corrupted(seg: etherparse::PacketBuilderStep<etherparse::TcpHeader>, offset: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    seg.write(&mut buf, &[]).unwrap();
+    buf[offset] ^= 0x10;
+    buf
+}
+
+#[test]
+fn bad_checksums_are_dropped_and_counted() {
+    let (mut server, mut peer) = scripted();
+    let _listener = server.bind(80).unwrap();
+
+    // the TTL is covered by the IP header checksum, the window by the TCP checksum
+    peer.send(&corrupted(syn(80), 8)).unwrap();
+    peer.send(&corrupted(syn(80), 20 + 14)).unwrap();
+    let mut buf = [0u8; 1500];
+    assert!(peer.recv(&mut buf, Instant::now() + Duration::from_millis(100)).unwrap().is_none());
+    assert_eq!(server.stats(), Stats { bad_ip_checksum: 1, bad_tcp_checksum: 1, ..Stats::default() });
+
+    // and an intact one still gets through
+    send_segment(&mut peer, syn(80));
+    assert!(recv_segment(&mut peer).syn);
+}
+
+/// A link that claims to check checksums itself
+struct Offloaded(Pipe);
+
+impl Device for Offloaded {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        self.0.recv(buf, deadline)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        self.0.mtu()
+    }
+
+    fn verifies_checksums(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn checksums_are_not_verified_twice() {
+    let (a, mut peer) = device::pipe(1500);
+    let mut server = InterfaceBuilder::new().address(SERVER, 24).build_with(Offloaded(a)).unwrap();
+    let _listener = server.bind(80).unwrap();
+
+    peer.send(&corrupted(syn(80), 20 + 14)).unwrap();
+    assert!(recv_segment(&mut peer).syn);
+    assert_eq!(server.stats(), Stats::default());
+}
+
+/// Accept a connection on SERVER:80 by playing the client's side of the handshake
+fn handshake(server: &mut Interface, peer: &mut Pipe) -> (trust::TcpStream, etherparse::TcpHeader) {
+    let listener = server.bind(80).unwrap();
+    send_segment(peer, syn(80));
+    let synack = recv_segment(peer);
+    send_segment(peer, etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+        .tcp(40000, 80, 1001, 65535)
+        .ack(synack.sequence_number.wrapping_add(1)));
+    (listener.accept().unwrap(), synack)
+}
+
+/// Receive a packet from the stack, returning it along with its TCP header and payload offset
+fn recv_packet(peer: &mut Pipe) -> (Vec<u8>, etherparse::TcpHeader, usize) {
+    let mut buf = [0u8; 1500];
+    let n = peer.recv(&mut buf, Instant::now() + Duration::from_secs(1)).unwrap().expect("no segment from the stack");
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(&buf[..n]).unwrap();
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..n]).unwrap();
+    let datai = iph.slice().len() + tcph.slice().len();
+    (buf[..n].to_vec(), tcph.to_header(), datai)
+}
+
+#[test]
+fn outgoing_checksums_cover_the_payload() {
+    let (mut server, mut peer) = scripted();
+    let (mut stream, synack) = handshake(&mut server, &mut peer);
+
+    // odd sized writes, and enough of them for the send queue to wrap around
+    let mut expected = Vec::new();
+    let mut received = Vec::new();
+    for i in 0..40u32 {
+        let chunk: Vec<u8> = (0..(101 + i * 7)).map(|j| (i * 31 + j) as u8).collect();
+        stream.write_all(&chunk).unwrap();
+        expected.extend_from_slice(&chunk);
+
+        while received.len() < expected.len() {
+            let (packet, tcph, datai) = recv_packet(&mut peer);
+            let iph = etherparse::Ipv4HeaderSlice::from_slice(&packet).unwrap().to_header();
+            assert_eq!(tcph.checksum, tcph.calc_checksum_ipv4(&iph, &packet[datai..]).unwrap());
+            received.extend_from_slice(&packet[datai..]);
+
+            let ackn = synack.sequence_number.wrapping_add(1 + received.len() as u32);
+            send_segment(&mut peer, etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+                .tcp(40000, 80, 1001, 65535)
+                .ack(ackn));
+        }
+    }
+    assert_eq!(received, expected);
+}
+
+/// A link that fills in the TCP checksum of what it sends
+struct ChecksumOffload(Pipe);
+
+impl Device for ChecksumOffload {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        self.0.recv(buf, deadline)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        self.0.mtu()
+    }
+
+    fn fills_checksums(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn checksums_can_be_left_to_the_link() {
+    let (a, mut peer) = device::pipe(1500);
+    let mut server = InterfaceBuilder::new().address(SERVER, 24).build_with(ChecksumOffload(a)).unwrap();
+    let _listener = server.bind(80).unwrap();
+    send_segment(&mut peer, syn(80));
+
+    let (packet, tcph, _) = recv_packet(&mut peer);
+    assert!(tcph.syn);
+    // the checksum field holds the sum of the pseudo header only
+    let mut pseudo = Vec::new();
+    pseudo.extend_from_slice(&SERVER.octets());
+    pseudo.extend_from_slice(&CLIENT.octets());
+    pseudo.extend_from_slice(&[0, 6]);
+    pseudo.extend_from_slice(&((packet.len() - 20) as u16).to_be_bytes());
+    let mut sum: u32 = pseudo.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]]) as u32).sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    assert_eq!(tcph.checksum, sum as u16);
+}
+
+#[test]
+fn udp_recv_blocks_until_a_datagram_arrives() {
+    let (mut client, mut server) = interfaces();
+    let socket = server.bind_udp(53).unwrap();
+
+    let jh = thread::spawn(move || {
+        let mut buf = [0u8; 16];
+        let (n, from) = socket.recv_from(&mut buf).unwrap();
+        socket.send_to(&buf[..n], from).unwrap();
+    });
+
+    let socket = client.bind_udp(5000).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    socket.send_to(b"echo", SocketAddrV4::new(SERVER, 53)).unwrap();
+    let mut buf = [0u8; 16];
+    let (n, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..n], from), (&b"echo"[..], SocketAddrV4::new(SERVER, 53).into()));
+    jh.join().unwrap();
+}
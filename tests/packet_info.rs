@@ -0,0 +1,94 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use trust::device::{self, Device, Pipe};
+use trust::{Interface, InterfaceBuilder};
+
+const STACK: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const STACK6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+const PEER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+const PEER_PORT: u16 = 40000;
+
+/// An interface on a link of packets with packet information in front, like a tun device opened
+/// with it, and the other end of the link for the test to play the peer
+fn link() -> (Interface, Pipe) {
+    let (a, b) = device::pipe(1504);
+    let iface = InterfaceBuilder::new().address(STACK, 24).address6(STACK6, 64).packet_info(true).build_with(a).unwrap();
+    (iface, b)
+}
+
+fn recv_packet(peer: &mut Pipe, timeout: Duration) -> Option<Vec<u8>> {
+    let mut buf = [0u8; 1504];
+    let n = peer.recv(&mut buf, Instant::now() + timeout).unwrap()?;
+    Some(buf[..n].to_vec())
+}
+
+/// `packet` with the packet information of `proto` in front
+fn with_info(proto: u16, packet: &[u8]) -> Vec<u8> {
+    let mut out = vec![0, 0];
+    out.extend_from_slice(&proto.to_be_bytes());
+    out.extend_from_slice(packet);
+    out
+}
+
+fn syn(builder: etherparse::PacketBuilderStep<etherparse::IpHeader>, port: u16) -> Vec<u8> {
+    let builder = builder.tcp(PEER_PORT, port, 1000, 65535).syn();
+    let mut packet = Vec::new();
+    builder.write(&mut packet, &[]).unwrap();
+    packet
+}
+
+#[test]
+fn ipv4_is_answered_with_its_packet_information() {
+    let (mut iface, mut peer) = link();
+    let _listener = iface.bind(80).unwrap();
+
+    let packet = syn(etherparse::PacketBuilder::ipv4(PEER.octets(), STACK.octets(), 64), 80);
+    peer.send(&with_info(0x0800, &packet)).unwrap();
+
+    let reply = recv_packet(&mut peer, Duration::from_secs(1)).expect("no answer to the syn");
+    assert_eq!(&reply[..4], &[0, 0, 0x08, 0x00]);
+    let (iph, rest) = etherparse::Ipv4Header::read_from_slice(&reply[4..]).unwrap();
+    assert_eq!((iph.source, iph.destination), (STACK.octets(), PEER.octets()));
+    let (tcph, _) = etherparse::TcpHeader::read_from_slice(rest).unwrap();
+    assert!(tcph.syn && tcph.ack);
+    assert_eq!(tcph.acknowledgment_number, 1001);
+}
+
+#[test]
+fn ipv6_is_answered_with_its_packet_information() {
+    let (_iface, mut peer) = link();
+
+    // nobody listens, so the stack resets
+    let packet = syn(etherparse::PacketBuilder::ipv6(PEER6.octets(), STACK6.octets(), 64), 81);
+    peer.send(&with_info(0x86dd, &packet)).unwrap();
+
+    let reply = recv_packet(&mut peer, Duration::from_secs(1)).expect("no answer to the syn");
+    assert_eq!(&reply[..4], &[0, 0, 0x86, 0xdd]);
+    let (iph, rest) = etherparse::Ipv6Header::read_from_slice(&reply[4..]).unwrap();
+    assert_eq!((iph.source, iph.destination), (STACK6.octets(), PEER6.octets()));
+    let (tcph, _) = etherparse::TcpHeader::read_from_slice(rest).unwrap();
+    assert!(tcph.rst && tcph.ack);
+}
+
+#[test]
+fn other_protocols_are_dropped_and_counted() {
+    let (iface, mut peer) = link();
+
+    // an IPv4 packet, but the packet information says ARP
+    let packet = syn(etherparse::PacketBuilder::ipv4(PEER.octets(), STACK.octets(), 64), 81);
+    peer.send(&with_info(0x0806, &packet)).unwrap();
+    // and a packet that is no IP at all
+    peer.send(&with_info(0x0800, &[0x10; 40])).unwrap();
+
+    assert!(recv_packet(&mut peer, Duration::from_millis(100)).is_none());
+    assert_eq!(iface.stats().unknown_protocol, 2);
+}
+
+#[test]
+fn packet_information_is_not_for_ethernet() {
+    let (a, _b) = device::pipe(1514);
+    let built = InterfaceBuilder::new().address(STACK, 24).ethernet([0x02, 0, 0, 0, 0, 2]).packet_info(true).build_with(a);
+    assert_eq!(built.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+}
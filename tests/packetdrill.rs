@@ -0,0 +1,405 @@
+//! Scripted tests of the TCP state machine, in the spirit of packetdrill.
+//!
+//! Each script in `tests/scripts` runs against an interface in a `Simulation`, with the test
+//! playing the peer. A line is a time followed by an event:
+//!
+//! ```text
+//! 0      listen 80                          call into the stack
+//! 0.1    < S 0:0(0) win 1000 <mss 1000>     inject a segment from the peer
+//! +0.01  > S. 0:0(0) ack 1 <mss 1460>       expect the stack to send this segment by then
+//! ```
+//!
+//! Times are seconds since the start, or since the previous line with a `+`. Segments use
+//! packetdrill's notation: flags (`S`, `F`, `R`, `P`, and `.` for ACK), `start:end(len)`, and
+//! optional `ack`, `win` and `<mss N>`. Sequence numbers of the stack are relative to its ISN,
+//! those of the peer are as written. Calls are `listen PORT`, `connect PORT`, `accept`,
+//! `write LEN`, `read LEN` (an error kind such as `ConnectionReset` instead of a length expects
+//! that error), `shutdown`, `close` and `unlisten`. Any segment the stack sends that no line
+//! expects fails the script, and so does a segment that arrives more than `TOLERANCE` from its
+//! expected time.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use trust::sim::Simulation;
+use trust::{Interface, InterfaceBuilder, TcpListener, TcpStream};
+
+mod common;
+
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const STACK: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PEER_PORT: u16 = 40000;
+const TOLERANCE: Duration = Duration::from_millis(25);
+
+#[derive(Debug, Default)]
+struct Segment {
+    syn: bool,
+    fin: bool,
+    rst: bool,
+    psh: bool,
+    ack: Option<u32>,
+    seq: u32,
+    len: u32,
+    win: Option<u16>,
+    mss: Option<u16>,
+}
+
+enum Event {
+    Inject(Segment),
+    Expect(Segment),
+    Call(String, Option<String>),
+}
+
+fn parse_segment(words: &[&str]) -> Result<Segment, String> {
+    let mut seg = Segment::default();
+    let mut words = words.iter();
+
+    let flags = words.next().ok_or("missing flags")?;
+    for f in flags.chars() {
+        match f {
+            'S' => seg.syn = true,
+            'F' => seg.fin = true,
+            'R' => seg.rst = true,
+            'P' => seg.psh = true,
+            // the ACK number follows later
+            '.' => seg.ack = Some(0),
+            _ => return Err(format!("unknown flag {}", f)),
+        }
+    }
+
+    // start:end(len)
+    let range = words.next().ok_or("missing sequence numbers")?;
+    let bad_range = || format!("bad sequence numbers {}", range);
+    let (start, rest) = range.split_once(':').ok_or_else(bad_range)?;
+    let (end, len) = rest.trim_end_matches(')').split_once('(').ok_or_else(bad_range)?;
+    seg.seq = start.parse().map_err(|_| bad_range())?;
+    seg.len = len.parse().map_err(|_| bad_range())?;
+    if end.parse::<u32>().map_err(|_| bad_range())? != seg.seq.wrapping_add(seg.len) {
+        return Err(bad_range());
+    }
+
+    while let Some(word) = words.next() {
+        let value = words.next().ok_or_else(|| format!("missing value of {}", word))?;
+        let number = |v: &str| v.trim_end_matches('>').parse().map_err(|_| format!("bad {} {}", word, v));
+        match *word {
+            "ack" => seg.ack = Some(number(value)?),
+            "win" => seg.win = Some(number(value)? as u16),
+            "<mss" => seg.mss = Some(number(value)? as u16),
+            _ => return Err(format!("unknown segment field {}", word)),
+        }
+    }
+    if seg.ack.is_some() && !flags.contains('.') {
+        return Err("ack number without the ACK flag".to_string());
+    }
+    Ok(seg)
+}
+
+fn parse(script: &str) -> Result<Vec<(Duration, Event, usize)>, String> {
+    let mut events = Vec::new();
+    let mut t = Duration::default();
+    for (i, line) in script.lines().enumerate() {
+        let lineno = i + 1;
+        let line = line.split("//").next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let at = |s: &str| s.parse::<f64>().map_err(|_| format!("line {}: bad time {}", lineno, s));
+        t = match words[0].strip_prefix('+') {
+            Some(delta) => t + Duration::from_secs_f64(at(delta)?),
+            None => Duration::from_secs_f64(at(words[0])?),
+        };
+
+        let event = match words.get(1) {
+            Some(&"<") => Event::Inject(parse_segment(&words[2..]).map_err(|e| format!("line {}: {}", lineno, e))?),
+            Some(&">") => Event::Expect(parse_segment(&words[2..]).map_err(|e| format!("line {}: {}", lineno, e))?),
+            Some(call) => Event::Call(call.to_string(), words.get(2).map(|s| s.to_string())),
+            None => return Err(format!("line {}: missing event", lineno)),
+        };
+        events.push((t, event, lineno));
+    }
+    Ok(events)
+}
+
+struct Runner {
+    sim: Simulation,
+    iface: Interface,
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    /// the stack's port, once known
+    port: Option<u16>,
+    peer_port: u16,
+    /// the stack's ISN, once it sent its SYN
+    isn: Option<u32>,
+    /// segments the stack sent that no line looked at yet
+    received: VecDeque<(Duration, Vec<u8>)>,
+}
+
+impl Runner {
+    fn new() -> Self {
+        let (sim, iface) = common::network(InterfaceBuilder::new().address(STACK, 24), PEER);
+        Runner {
+            sim,
+            iface,
+            listener: None,
+            stream: None,
+            port: None,
+            peer_port: PEER_PORT,
+            isn: None,
+            received: VecDeque::new(),
+        }
+    }
+
+    fn next_received(&mut self) -> Option<(Duration, Vec<u8>)> {
+        self.received.extend(self.sim.take_received());
+        self.received.pop_front()
+    }
+
+    fn run_to(&mut self, t: Duration) -> Result<(), String> {
+        if let Some(d) = t.checked_sub(self.sim.elapsed()) {
+            self.sim.run_for(d).map_err(|e| e.to_string())?;
+        }
+        match self.next_received() {
+            Some((at, packet)) => Err(format!("unexpected segment at {:?}: {}", at, describe(&packet))),
+            None => Ok(()),
+        }
+    }
+
+    fn inject(&mut self, seg: &Segment) -> Result<(), String> {
+        let port = self.port.ok_or("no port to send to yet")?;
+        if seg.syn && seg.ack.is_none() {
+            // a new connection: whatever the stack answers starts from scratch, and a reset
+            // of the SYN carries sequence number 0
+            self.isn = None;
+        }
+        let isn = self.isn.unwrap_or(0);
+        let mut tcp = etherparse::TcpHeader::new(self.peer_port, port, seg.seq, seg.win.unwrap_or(65535));
+        tcp.syn = seg.syn;
+        tcp.fin = seg.fin;
+        tcp.rst = seg.rst;
+        tcp.psh = seg.psh;
+        if let Some(ack) = seg.ack {
+            tcp.ack = true;
+            tcp.acknowledgment_number = isn.wrapping_add(ack);
+        }
+        if let Some(mss) = seg.mss {
+            tcp.set_options(&[etherparse::TcpOptionElement::MaximumSegmentSize(mss)]).unwrap();
+        }
+
+        let payload = vec![b'x'; seg.len as usize];
+        let mut ip = etherparse::Ipv4Header::new(
+            (tcp.header_len() as usize + payload.len()) as u16,
+            64,
+            etherparse::IpTrafficClass::Tcp,
+            PEER.octets(),
+            STACK.octets());
+        ip.header_checksum = ip.calc_header_checksum().unwrap();
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, &payload).unwrap();
+
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(&payload);
+        self.sim.send(packet);
+        Ok(())
+    }
+
+    fn expect(&mut self, t: Duration, want: &Segment) -> Result<(), String> {
+        loop {
+            if let Some((at, packet)) = self.next_received() {
+                if at + TOLERANCE < t {
+                    return Err(format!("segment at {:?} came too early: {}", at, describe(&packet)));
+                }
+                return self.check(&packet, want);
+            }
+            if self.sim.elapsed() > t + TOLERANCE {
+                return Err("expected segment was not sent".to_string());
+            }
+            self.sim.run_for(Duration::from_millis(1)).map_err(|e| e.to_string())?;
+        }
+    }
+
+    fn check(&mut self, packet: &[u8], want: &Segment) -> Result<(), String> {
+        let iph = etherparse::Ipv4HeaderSlice::from_slice(packet).map_err(|e| format!("{:?}", e))?;
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).map_err(|e| format!("{:?}", e))?;
+        let len = (packet.len() - iph.slice().len() - tcph.slice().len()) as u32;
+        let got = describe(packet);
+
+        if want.syn && self.isn.is_none() {
+            self.isn = Some(tcph.sequence_number());
+        }
+        if self.port.is_none() {
+            self.port = Some(tcph.source_port());
+        }
+        let isn = self.isn.unwrap_or(0);
+        let mss = tcph.options_iterator().find_map(|o| match o {
+            Ok(etherparse::TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+            _ => None,
+        });
+
+        let matches = tcph.syn() == want.syn
+            && tcph.fin() == want.fin
+            && tcph.rst() == want.rst
+            && tcph.psh() == want.psh
+            && tcph.ack() == want.ack.is_some()
+            && want.ack.is_none_or(|ack| tcph.acknowledgment_number() == ack)
+            && tcph.sequence_number().wrapping_sub(isn) == want.seq
+            && len == want.len
+            && want.win.is_none_or(|win| tcph.window_size() == win)
+            && want.mss.is_none_or(|want| mss == Some(want))
+            && tcph.destination_port() == self.peer_port;
+        if matches {
+            Ok(())
+        } else {
+            Err(format!("expected {:?}, got {}", want, got))
+        }
+    }
+
+    fn call(&mut self, call: &str, arg: Option<&str>) -> Result<(), String> {
+        let number = || arg.and_then(|a| a.parse::<usize>().ok()).ok_or(format!("{} needs a number", call));
+        match call {
+            "listen" => {
+                let port = number()? as u16;
+                self.listener = Some(self.iface.bind(port).map_err(|e| e.to_string())?);
+                self.port = Some(port);
+            }
+            "connect" => {
+                self.peer_port = number()? as u16;
+                let stream = self.iface.connect(SocketAddrV4::new(PEER, self.peer_port)).map_err(|e| e.to_string())?;
+                self.stream = Some(stream);
+            }
+            "unlisten" => {
+                self.listener.take().ok_or("unlisten without listen")?;
+            }
+            "accept" => {
+                let listener = self.listener.as_ref().ok_or("accept without listen")?;
+                self.stream = Some(listener.accept().map_err(|e| format!("accept failed: {}", e))?);
+            }
+            "write" => {
+                let n = number()?;
+                let stream = self.stream.as_mut().ok_or("no connection")?;
+                let written = stream.write(&vec![b'y'; n]).map_err(|e| format!("write failed: {}", e))?;
+                if written != n {
+                    return Err(format!("wrote {} bytes instead of {}", written, n));
+                }
+            }
+            "read" => {
+                let stream = self.stream.as_mut().ok_or("no connection")?;
+                let mut buf = vec![0u8; 65536];
+                let r = stream.read(&mut buf);
+                let want = arg.ok_or("read needs a length or an error")?;
+                match (r, want.parse::<usize>()) {
+                    (Ok(n), Ok(want)) if n == want => {}
+                    (Err(e), Err(_)) if format!("{:?}", e.kind()) == want => {}
+                    (r, _) => return Err(format!("read returned {:?}, expected {}", r, want)),
+                }
+            }
+            "shutdown" => {
+                let stream = self.stream.as_ref().ok_or("no connection")?;
+                stream.shutdown(std::net::Shutdown::Write).map_err(|e| e.to_string())?;
+            }
+            "close" => {
+                self.stream.take().ok_or("no connection")?;
+            }
+            _ => return Err(format!("unknown call {}", call)),
+        }
+        Ok(())
+    }
+}
+
+fn describe(packet: &[u8]) -> String {
+    let iph = match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+        Ok(iph) => iph,
+        Err(e) => return format!("{:?}", e),
+    };
+    match etherparse::TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]) {
+        Ok(tcph) => format!(
+            "{}{}{}{}{} seq {} ack {} win {} len {}",
+            if tcph.syn() { "S" } else { "" },
+            if tcph.fin() { "F" } else { "" },
+            if tcph.rst() { "R" } else { "" },
+            if tcph.psh() { "P" } else { "" },
+            if tcph.ack() { "." } else { "" },
+            tcph.sequence_number(),
+            tcph.acknowledgment_number(),
+            tcph.window_size(),
+            packet.len() - iph.slice().len() - tcph.slice().len()),
+        Err(e) => format!("{:?}", e),
+    }
+}
+
+fn run(name: &str) {
+    let path = format!("{}/tests/scripts/{}.pkt", env!("CARGO_MANIFEST_DIR"), name);
+    let script = fs::read_to_string(&path).unwrap();
+    run_script(&path, &script);
+}
+
+fn run_script(path: &str, script: &str) {
+    let events = parse(script).unwrap_or_else(|e| panic!("{}: {}", path, e));
+
+    let mut runner = Runner::new();
+    let mut last = Duration::ZERO;
+    for (t, event, lineno) in events {
+        last = t;
+        let r = match event {
+            Event::Inject(seg) => runner.run_to(t).and_then(|_| runner.inject(&seg)),
+            Event::Expect(seg) => runner.expect(t, &seg),
+            Event::Call(call, arg) => runner.run_to(t).and_then(|_| runner.call(&call, arg.as_deref())),
+        };
+        if let Err(e) = r {
+            panic!("{}:{}: {}", path, lineno, e);
+        }
+    }
+    // nothing may follow the last line either
+    if let Err(e) = runner.run_to(last + TOLERANCE) {
+        panic!("{}: after the last line: {}", path, e);
+    }
+}
+
+macro_rules! scripts {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                run(stringify!($name));
+            }
+        )*
+    };
+}
+
+scripts! {
+    handshake_passive,
+    handshake_active,
+    handshake_refused,
+    syn_to_closed_port,
+    data_receive,
+    data_send,
+    data_out_of_order,
+    zero_window,
+    close_active,
+    close_passive,
+    rst_in_established,
+    syn_ack_retransmit,
+    data_retransmit,
+}
+
+#[test]
+fn unexpected_segments_fail_the_script() {
+    let mut runner = Runner::new();
+    runner.call("listen", Some("80")).unwrap();
+    runner.inject(&parse_segment(&["S", "0:0(0)", "win", "1000"]).unwrap()).unwrap();
+    // nothing expects the SYN-ACK
+    let e = runner.run_to(Duration::from_millis(10)).unwrap_err();
+    assert!(e.starts_with("unexpected segment"), "{}", e);
+}
+
+#[test]
+#[should_panic(expected = "after the last line: unexpected segment")]
+fn segments_after_the_last_line_fail_the_script() {
+    // nothing expects the SYN-ACK
+    run_script("inline", "0 listen 80\n+0 < S 0:0(0) win 1000\n");
+}
@@ -0,0 +1,156 @@
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use trust::sim::Simulation;
+use trust::{Interface, InterfaceBuilder, TcpStream};
+
+mod common;
+use common::checksum;
+
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const STACK: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PEER_PORT: u16 = 40000;
+
+/// A connection from the peer to the stack, with the stack's ISN
+fn connected() -> (Simulation, Interface, TcpStream, u32) {
+    let (mut sim, mut iface) = common::network(InterfaceBuilder::new().address(STACK, 24).send_buffer_size(64 * 1024), PEER);
+    let listener = iface.bind(80).unwrap();
+
+    sim.send(segment(0, None, true));
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    let (_, synack) = sim.take_received().remove(0);
+    let isn = tcp(&synack).sequence_number;
+    sim.send(segment(1, Some(isn.wrapping_add(1)), false));
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    let stream = listener.accept().unwrap();
+    (sim, iface, stream, isn)
+}
+
+fn segment(seq: u32, ack: Option<u32>, syn: bool) -> Vec<u8> {
+    let mut builder = etherparse::PacketBuilder::ipv4(PEER.octets(), STACK.octets(), 64)
+        .tcp(PEER_PORT, 80, seq, 8192);
+    if syn {
+        builder = builder.syn().options(&[etherparse::TcpOptionElement::MaximumSegmentSize(1460)]).unwrap();
+    }
+    if let Some(ack) = ack {
+        builder = builder.ack(ack);
+    }
+    let mut packet = Vec::new();
+    builder.write(&mut packet, &[]).unwrap();
+    packet
+}
+
+fn tcp(packet: &[u8]) -> etherparse::TcpHeader {
+    let (_, rest) = etherparse::Ipv4Header::read_from_slice(packet).unwrap();
+    etherparse::TcpHeader::read_from_slice(rest).unwrap().0
+}
+
+/// A router's "fragmentation needed" about `packet`
+fn too_big(packet: &[u8], mtu: u16) -> Vec<u8> {
+    let mut message = vec![3, 4, 0, 0, 0, 0];
+    message.extend_from_slice(&mtu.to_be_bytes());
+    message.extend_from_slice(&packet[..28]);
+    let sum = checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let ip = etherparse::Ipv4Header::new(message.len() as u16, 64, etherparse::IpTrafficClass::Icmp, [10, 0, 0, 254], STACK.octets());
+    let mut icmp = Vec::new();
+    ip.write(&mut icmp).unwrap();
+    icmp.extend_from_slice(&message);
+    icmp
+}
+
+/// A receiver behind a path that drops packets larger than `path_mtu`. Acks what arrives in
+/// order for `duration`, and returns the data and the sizes of the packets that got through.
+fn receive(sim: &mut Simulation, isn: u32, path_mtu: usize, duration: Duration) -> (Vec<u8>, Vec<usize>) {
+    let mut data = Vec::new();
+    let mut sizes = Vec::new();
+    let mut nxt = isn.wrapping_add(1);
+    let end = sim.elapsed() + duration;
+    while sim.elapsed() < end {
+        sim.run_for(Duration::from_millis(10)).unwrap();
+        for (_, packet) in sim.take_received() {
+            if packet.len() > path_mtu {
+                continue;
+            }
+            sizes.push(packet.len());
+            let header = tcp(&packet);
+            let payload = &packet[20 + header.header_len() as usize..];
+            if header.sequence_number == nxt && !payload.is_empty() {
+                data.extend_from_slice(payload);
+                nxt = nxt.wrapping_add(payload.len() as u32);
+            }
+            sim.send(segment(1, Some(nxt), false));
+        }
+    }
+    (data, sizes)
+}
+
+#[test]
+fn fragmentation_needed_shrinks_segments() {
+    let (mut sim, _iface, mut stream, isn) = connected();
+    let data: Vec<u8> = (0..6000u32).map(|i| i as u8).collect();
+    stream.write_all(&data).unwrap();
+    sim.run_for(Duration::from_millis(20)).unwrap();
+
+    let sent = sim.take_received();
+    let first = &sent[0].1;
+    assert_eq!(first.len(), 1500);
+    assert!(etherparse::Ipv4HeaderSlice::from_slice(first).unwrap().dont_fragment());
+
+    sim.send(too_big(first, 1000));
+    let (received, sizes) = receive(&mut sim, isn, 1000, Duration::from_secs(1));
+    assert_eq!(received, data);
+    assert!(sizes.iter().all(|&size| size <= 1000));
+    assert!(sizes.contains(&1000));
+}
+
+#[test]
+fn fragmentation_needed_without_mtu_uses_the_plateaus() {
+    let (mut sim, _iface, mut stream, isn) = connected();
+    let data = vec![1u8; 3000];
+    stream.write_all(&data).unwrap();
+    sim.run_for(Duration::from_millis(20)).unwrap();
+
+    let sent = sim.take_received();
+    sim.send(too_big(&sent[0].1, 0));
+    let (received, sizes) = receive(&mut sim, isn, 1492, Duration::from_secs(1));
+    assert_eq!(received, data);
+    assert!(sizes.contains(&1492));
+}
+
+#[test]
+fn messages_about_unknown_segments_are_ignored() {
+    let (mut sim, iface, mut stream, isn) = connected();
+    stream.write_all(&[2u8; 3000]).unwrap();
+    sim.run_for(Duration::from_millis(20)).unwrap();
+    let sent = sim.take_received();
+
+    // a sequence number we never sent
+    let mut forged = sent[0].1.clone();
+    forged[24..28].copy_from_slice(&isn.wrapping_add(100_000).to_be_bytes());
+    sim.send(too_big(&forged, 600));
+    // and a broken checksum
+    let mut corrupted = too_big(&sent[0].1, 600);
+    corrupted[30] ^= 1;
+    sim.send(corrupted);
+
+    // a genuine message would have the segments sent again right away
+    let (_, sizes) = receive(&mut sim, isn, 1500, Duration::from_millis(200));
+    assert!(sizes.is_empty(), "sent again: {:?}", sizes);
+    assert_eq!(iface.stats().bad_icmp_checksum, 1);
+}
+
+#[test]
+fn black_holes_are_probed_around() {
+    let (mut sim, _iface, mut stream, isn) = connected();
+    let data: Vec<u8> = (0..60_000u32).map(|i| (i % 253) as u8).collect();
+    stream.write_all(&data).unwrap();
+
+    // nothing tells the stack that packets over 1200 bytes are dropped
+    let (received, sizes) = receive(&mut sim, isn, 1200, Duration::from_secs(60));
+    assert_eq!(received, data);
+    let largest = sizes.iter().copied().max().unwrap();
+    assert!(largest > 1024 && largest <= 1200, "largest packet {}", largest);
+}
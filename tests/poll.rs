@@ -0,0 +1,126 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use trust::{Event, Interface, Poller, Ready, TcpStream};
+
+mod common;
+use common::{interfaces, SERVER};
+
+/// `n` connections from the client to the server, as (client side, server side)
+fn connections(client: &mut Interface, server: &mut Interface, n: usize) -> Vec<(TcpStream, TcpStream)> {
+    let listener = server.bind(80).unwrap();
+    (0..n).map(|_| {
+        let stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+        (stream, listener.accept().unwrap())
+    }).collect()
+}
+
+fn poll(poller: &mut Poller, timeout: Duration) -> Vec<Event> {
+    let mut events = Vec::new();
+    poller.poll(&mut events, Some(timeout)).unwrap();
+    events.sort_by_key(|e| e.key);
+    events
+}
+
+#[test]
+fn nonblocking_reads_and_accepts_would_block() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    assert_eq!(listener.accept().err().unwrap().kind(), io::ErrorKind::WouldBlock);
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut accepted = loop {
+        match listener.accept() {
+            Ok(s) => break s,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    };
+
+    accepted.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(accepted.read(&mut buf).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+
+    stream.write_all(b"hello").unwrap();
+    accepted.set_nonblocking(false).unwrap();
+    assert_eq!(accepted.read(&mut buf).unwrap(), 5);
+}
+
+#[test]
+fn poll_reports_what_is_ready() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+    let mut poller = Poller::new();
+    poller.register_listener(&listener, 0, Ready::ACCEPTABLE);
+    assert!(poll(&mut poller, Duration::from_millis(50)).is_empty());
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    assert_eq!(poll(&mut poller, Duration::from_secs(1)), vec![Event { key: 0, ready: Ready::ACCEPTABLE }]);
+    let mut accepted = listener.accept().unwrap();
+
+    poller.deregister(0);
+    poller.register_stream(&accepted, 1, Ready::READABLE | Ready::WRITABLE);
+    assert_eq!(poll(&mut poller, Duration::from_secs(1)), vec![Event { key: 1, ready: Ready::WRITABLE }]);
+
+    poller.register_stream(&accepted, 1, Ready::READABLE);
+    assert!(poll(&mut poller, Duration::from_millis(50)).is_empty());
+    stream.write_all(b"hello").unwrap();
+    assert_eq!(poll(&mut poller, Duration::from_secs(1)), vec![Event { key: 1, ready: Ready::READABLE }]);
+    // until it is read
+    assert_eq!(poll(&mut poller, Duration::from_secs(1)), vec![Event { key: 1, ready: Ready::READABLE }]);
+    assert_eq!(accepted.read(&mut [0u8; 16]).unwrap(), 5);
+    assert!(poll(&mut poller, Duration::from_millis(50)).is_empty());
+
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(poll(&mut poller, Duration::from_secs(1)), vec![Event { key: 1, ready: Ready::READABLE | Ready::HUP }]);
+}
+
+#[test]
+fn poll_wakes_up_for_any_of_many_streams() {
+    let (mut client, mut server) = interfaces();
+    let mut pairs = connections(&mut client, &mut server, 16);
+    let mut poller = Poller::new();
+    for (key, (_, accepted)) in pairs.iter().enumerate() {
+        poller.register_stream(accepted, key, Ready::READABLE);
+    }
+
+    let (mut stream, _) = pairs.remove(11);
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"hello").unwrap();
+        stream
+    });
+
+    let start = Instant::now();
+    let mut events = Vec::new();
+    poller.poll(&mut events, None).unwrap();
+    assert_eq!(events, vec![Event { key: 11, ready: Ready::READABLE }]);
+    assert!(start.elapsed() >= Duration::from_millis(40));
+    writer.join().unwrap();
+}
+
+#[test]
+fn poll_reports_closed_listeners_and_interfaces() {
+    let (mut client, mut server) = interfaces();
+    let mut pairs = connections(&mut client, &mut server, 1);
+    let listener = server.bind(81).unwrap();
+    let mut poller = Poller::new();
+    poller.register_listener(&listener, 0, Ready::ACCEPTABLE);
+    poller.register_stream(&pairs[0].0, 1, Ready::READABLE);
+
+    listener.close().unwrap();
+    assert_eq!(poll(&mut poller, Duration::from_secs(1)), vec![Event { key: 0, ready: Ready::ACCEPTABLE | Ready::HUP }]);
+    poller.deregister(0);
+
+    drop(pairs.remove(0).1);
+    drop(server);
+    // the client gets the RST of the server going away
+    let events = poll(&mut poller, Duration::from_secs(1));
+    assert_eq!(events.len(), 1);
+    assert!(events[0].ready.contains(Ready::READABLE | Ready::ERROR));
+}
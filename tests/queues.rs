@@ -0,0 +1,121 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use trust::device::{self, Device, Pipe};
+use trust::{InterfaceBuilder, Teardown};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// The client's side of a multi-queue link that, unlike the kernel, pays no attention to flows:
+/// it sends each packet on the next queue in turn, and receives from all of them
+struct Spread {
+    queues: Vec<Pipe>,
+    next: usize,
+}
+
+impl Device for Spread {
+    fn recv(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        loop {
+            for queue in &mut self.queues {
+                if let Some(n) = queue.recv(buf, Instant::now())? {
+                    return Ok(Some(n));
+                }
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.next = (self.next + 1) % self.queues.len();
+        self.queues[self.next].send(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        1500
+    }
+}
+
+#[test]
+fn connections_spread_over_queues_transfer_data() {
+    let (client_ends, server_ends): (Vec<Pipe>, Vec<Pipe>) = (0..4).map(|_| device::pipe(1500)).unzip();
+    let mut client = InterfaceBuilder::new()
+        .address(CLIENT, 24)
+        .build_with(Spread { queues: client_ends, next: 0 })
+        .unwrap();
+    let mut server = InterfaceBuilder::new().address(SERVER, 24).build_with_queues(server_ends).unwrap();
+    let listener = server.bind(80).unwrap();
+
+    let mut threads = Vec::new();
+    for i in 0..16u8 {
+        let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+        let mut accepted = listener.accept().unwrap();
+        // every packet of a connection goes to another queue than the last one
+        threads.push(thread::spawn(move || accepted.write_all(&[i; 4096]).unwrap()));
+        threads.push(thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            stream.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == i));
+        }));
+    }
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    drop(listener);
+    server.shutdown(Teardown::Graceful(Duration::from_secs(1))).unwrap();
+}
+
+#[test]
+#[cfg(feature = "test-hooks")]
+fn each_connection_stays_with_one_worker() {
+    let (client_ends, server_ends): (Vec<Pipe>, Vec<Pipe>) = (0..4).map(|_| device::pipe(1500)).unzip();
+    let mut client = InterfaceBuilder::new()
+        .address(CLIENT, 24)
+        .build_with(Spread { queues: client_ends, next: 0 })
+        .unwrap();
+    let mut server = InterfaceBuilder::new().address(SERVER, 24).build_with_queues(server_ends).unwrap();
+    let listener = server.bind(80).unwrap();
+
+    let mut pairs = Vec::new();
+    for i in 0..16u8 {
+        let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+        let mut accepted = listener.accept().unwrap();
+        // less than fits into the buffers, so one thread can play both sides
+        stream.write_all(&[i; 512]).unwrap();
+        let mut buf = [0u8; 512];
+        accepted.read_exact(&mut buf).unwrap();
+        accepted.write_all(&buf).unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        pairs.push((stream, accepted));
+    }
+    // their packets came in on every queue, but only ever went to the worker that owns them
+    let mut used = std::collections::HashSet::new();
+    for (_, accepted) in &pairs {
+        let workers = accepted.workers();
+        assert_eq!(workers.len(), 1, "processed by workers {:?}", workers);
+        used.extend(workers);
+    }
+    assert!(used.len() > 1);
+}
+
+#[test]
+fn queue_counts_are_checked() {
+    let none: Vec<Pipe> = Vec::new();
+    let e = InterfaceBuilder::new().build_with_queues(none).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    let too_many = (0..17).map(|_| device::pipe(1500).0).collect();
+    let e = InterfaceBuilder::new().build_with_queues(too_many).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    let ethernet = (0..2).map(|_| device::pipe(1500).0).collect();
+    let e = InterfaceBuilder::new().ethernet([2, 0, 0, 0, 0, 1]).build_with_queues(ethernet).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,137 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::time::Duration;
+
+use trust::impair::Impairments;
+use trust::sim::Simulation;
+use trust::InterfaceBuilder;
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn lossy() -> Impairments {
+    "delay 5ms 3ms loss 10% duplicate 5% reorder 10%".parse().unwrap()
+}
+
+fn would_block<T>(r: io::Result<T>) -> Option<T> {
+    match r {
+        Ok(t) => Some(t),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+/// Send `data` from the client to the server, and return what the server read and the trace
+fn transfer(seed: u64, link: Impairments, data: &[u8]) -> (Vec<u8>, Vec<(Duration, Vec<u8>)>) {
+    let mut sim = Simulation::new(seed, link);
+    let mut client = sim.add_host(InterfaceBuilder::new().address(CLIENT, 24)).unwrap();
+    let mut server = sim.add_host(InterfaceBuilder::new().address(SERVER, 24)).unwrap();
+    let listener = server.bind(80).unwrap();
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    let mut accepted = None;
+    let mut sent = 0;
+    let mut shut = false;
+    let mut received = Vec::new();
+    let mut eof = false;
+
+    let done = sim.run_until(Duration::from_secs(600), || {
+        if sent < data.len() {
+            sent += would_block(stream.write(&data[sent..])).unwrap_or(0);
+        } else if !shut {
+            stream.shutdown(Shutdown::Write).unwrap();
+            shut = true;
+        }
+
+        if accepted.is_none() {
+            accepted = would_block(listener.accept());
+        }
+        if let Some(s) = accepted.as_mut() {
+            let mut buf = [0u8; 2048];
+            if let Some(n) = would_block(s.read(&mut buf)) {
+                received.extend_from_slice(&buf[..n]);
+                eof = n == 0;
+            }
+        }
+        eof
+    }).unwrap();
+    assert!(done, "transfer did not finish");
+
+    (received, sim.trace().to_vec())
+}
+
+#[test]
+fn transfer_survives_a_bad_link() {
+    let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    for seed in 0..8 {
+        let (received, _) = transfer(seed, lossy(), &data);
+        assert_eq!(received, data, "seed {}", seed);
+    }
+}
+
+#[test]
+fn same_seed_replays_the_same_packets() {
+    let data = vec![7u8; 5000];
+    let (_, first) = transfer(42, lossy(), &data);
+    let (_, second) = transfer(42, lossy(), &data);
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+
+    let (_, other) = transfer(43, lossy(), &data);
+    assert_ne!(first, other);
+}
+
+#[test]
+fn connect_times_out_without_a_peer() {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let mut client = sim.add_host(InterfaceBuilder::new().address(CLIENT, 24)).unwrap();
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+
+    let mut err = None;
+    let done = sim.run_until(Duration::from_secs(3600), || {
+        match stream.read(&mut [0u8; 1]) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            r => {
+                err = r.err();
+                true
+            }
+        }
+    }).unwrap();
+    assert!(done);
+    assert_eq!(err.unwrap().kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn corrupted_packets_are_dropped() {
+    let mut sim = Simulation::new(9, "delay 5ms corrupt 10%".parse().unwrap());
+    let mut client = sim.add_host(InterfaceBuilder::new().address(CLIENT, 24)).unwrap();
+    let mut server = sim.add_host(InterfaceBuilder::new().address(SERVER, 24)).unwrap();
+    let listener = server.bind(80).unwrap();
+    let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    let mut accepted = None;
+    let mut sent = 0;
+    let mut received = Vec::new();
+    let done = sim.run_until(Duration::from_secs(600), || {
+        if sent < data.len() {
+            sent += would_block(stream.write(&data[sent..])).unwrap_or(0);
+        }
+        if accepted.is_none() {
+            accepted = would_block(listener.accept());
+        }
+        if let Some(s) = accepted.as_mut() {
+            let mut buf = [0u8; 2048];
+            if let Some(n) = would_block(s.read(&mut buf)) {
+                received.extend_from_slice(&buf[..n]);
+            }
+        }
+        received.len() == data.len()
+    }).unwrap();
+    assert!(done);
+    assert_eq!(received, data);
+
+    let (c, s) = (client.stats(), server.stats());
+    assert!(c.bad_ip_checksum + c.bad_tcp_checksum + s.bad_ip_checksum + s.bad_tcp_checksum > 0);
+}
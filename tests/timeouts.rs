@@ -0,0 +1,108 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use trust::device;
+use trust::{Interface, InterfaceBuilder, TcpStream};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn interfaces(client: InterfaceBuilder, server: InterfaceBuilder) -> (Interface, Interface) {
+    let (a, b) = device::pipe(1500);
+    let client = client.address(CLIENT, 24).build_with(a).unwrap();
+    let server = server.address(SERVER, 24).build_with(b).unwrap();
+    (client, server)
+}
+
+/// A connection from the client to the server, as (client side, server side)
+fn connection(client: &mut Interface, server: &mut Interface) -> (TcpStream, TcpStream) {
+    let listener = server.bind(80).unwrap();
+    let stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    (stream, listener.accept().unwrap())
+}
+
+#[test]
+fn reads_time_out() {
+    let (mut client, mut server) = interfaces(InterfaceBuilder::new(), InterfaceBuilder::new());
+    let (mut stream, mut accepted) = connection(&mut client, &mut server);
+
+    assert_eq!(accepted.read_timeout().unwrap(), None);
+    accepted.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    assert_eq!(accepted.read_timeout().unwrap(), Some(Duration::from_millis(50)));
+
+    let start = Instant::now();
+    let mut buf = [0u8; 16];
+    assert_eq!(accepted.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // and still read what comes in in time
+    stream.write_all(b"hello").unwrap();
+    assert_eq!(accepted.read(&mut buf).unwrap(), 5);
+}
+
+#[test]
+fn zero_timeouts_are_refused() {
+    let (mut client, mut server) = interfaces(InterfaceBuilder::new(), InterfaceBuilder::new());
+    let (stream, _accepted) = connection(&mut client, &mut server);
+    assert_eq!(stream.set_read_timeout(Some(Duration::ZERO)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(stream.set_write_timeout(Some(Duration::ZERO)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    stream.set_write_timeout(Some(Duration::from_secs(1))).unwrap();
+    stream.set_write_timeout(None).unwrap();
+    assert_eq!(stream.write_timeout().unwrap(), None);
+}
+
+#[test]
+fn writes_wait_for_room_and_time_out() {
+    // the server takes 1024 bytes and then closes its window, as nobody reads
+    let (mut client, mut server) = interfaces(
+        InterfaceBuilder::new().send_buffer_size(1024),
+        InterfaceBuilder::new().recv_buffer_size(1024),
+    );
+    let (mut stream, mut accepted) = connection(&mut client, &mut server);
+    stream.set_write_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    let data = [7u8; 512];
+    let mut sent = 0;
+    let err = loop {
+        let start = Instant::now();
+        match stream.write(&data) {
+            Ok(n) => sent += n,
+            Err(e) => {
+                assert!(start.elapsed() >= Duration::from_millis(100));
+                break e;
+            }
+        }
+        assert!(sent <= 4096, "the send buffer never filled up");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    // reading on the other end makes room again, and a blocked write goes through
+    stream.set_write_timeout(None).unwrap();
+    let reader = thread::spawn(move || {
+        let mut received = 0;
+        let mut buf = [0u8; 512];
+        while received < sent + data.len() {
+            received += accepted.read(&mut buf).unwrap();
+        }
+        accepted
+    });
+    stream.write_all(&data).unwrap();
+    reader.join().unwrap();
+}
+
+#[test]
+fn accepts_time_out() {
+    let (mut client, mut server) = interfaces(InterfaceBuilder::new(), InterfaceBuilder::new());
+    let listener = server.bind(80).unwrap();
+
+    let start = Instant::now();
+    let err = listener.accept_timeout(Duration::from_millis(50)).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    let _stream = client.connect(SocketAddrV4::new(SERVER, 80)).unwrap();
+    assert!(listener.accept_timeout(Duration::from_secs(1)).is_ok());
+}
@@ -0,0 +1,86 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use trust::impair::Impairments;
+use trust::sim::Simulation;
+use trust::{InterfaceBuilder, UdpSocket};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+fn recv(socket: &UdpSocket) -> Option<(Vec<u8>, SocketAddr)> {
+    let mut buf = [0u8; 1500];
+    match socket.recv_from(&mut buf) {
+        Ok((n, from)) => Some((buf[..n].to_vec(), from)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+#[test]
+fn datagrams_go_both_ways() {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let mut client = sim.add_host(InterfaceBuilder::new().address(CLIENT, 24)).unwrap();
+    let mut server = sim.add_host(InterfaceBuilder::new().address(SERVER, 24)).unwrap();
+    let a = client.bind_udp(5000).unwrap();
+    let b = server.bind_udp(53).unwrap();
+    assert_eq!(b.local_addr(), SocketAddr::from((SERVER, 53)));
+
+    a.send_to(b"query", SocketAddr::from((SERVER, 53))).unwrap();
+    a.send_to(b"another", SocketAddr::from((SERVER, 53))).unwrap();
+    assert!(recv(&b).is_none());
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    assert_eq!(recv(&b), Some((b"query".to_vec(), SocketAddr::from((CLIENT, 5000)))));
+    assert_eq!(recv(&b), Some((b"another".to_vec(), SocketAddr::from((CLIENT, 5000)))));
+    assert!(recv(&b).is_none());
+
+    b.send_to(b"answer", SocketAddr::from((CLIENT, 5000))).unwrap();
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    assert_eq!(recv(&a), Some((b"answer".to_vec(), SocketAddr::from((SERVER, 53)))));
+}
+
+#[test]
+fn unbound_ports_are_unreachable() {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let _server = sim.add_host(InterfaceBuilder::new().address(SERVER, 24)).unwrap();
+    sim.add_peer(PEER).unwrap();
+
+    let mut datagram = Vec::new();
+    etherparse::PacketBuilder::ipv4(PEER.octets(), SERVER.octets(), 64)
+        .udp(4000, 53)
+        .write(&mut datagram, b"anyone?")
+        .unwrap();
+    sim.send(datagram.clone());
+    sim.run_for(Duration::from_millis(50)).unwrap();
+
+    let received = sim.take_received();
+    assert_eq!(received.len(), 1);
+    let (iph, message) = etherparse::Ipv4Header::read_from_slice(&received[0].1).unwrap();
+    assert_eq!((iph.protocol, iph.destination), (1, PEER.octets()));
+    assert_eq!(&message[..2], &[3, 3]);
+    assert_eq!(&message[8..], &datagram[..]);
+}
+
+#[test]
+fn ports_are_bound_once() {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let mut server = sim.add_host(InterfaceBuilder::new().address(SERVER, 24)).unwrap();
+
+    let socket = server.bind_udp(53).unwrap();
+    assert_eq!(server.bind_udp(53).err().unwrap().kind(), io::ErrorKind::AddrInUse);
+    drop(socket);
+    server.bind_udp(53).unwrap();
+}
+
+#[test]
+fn datagrams_must_fit_the_mtu() {
+    let mut sim = Simulation::new(0, Impairments::default());
+    let mut client = sim.add_host(InterfaceBuilder::new().address(CLIENT, 24).mtu(576)).unwrap();
+    let socket = client.bind_udp(5000).unwrap();
+    let to = SocketAddr::from((SERVER, 53));
+
+    assert_eq!(socket.send_to(&[0; 548], to).unwrap(), 548);
+    assert_eq!(socket.send_to(&[0; 549], to).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+}
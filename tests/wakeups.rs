@@ -0,0 +1,42 @@
+#![cfg(feature = "test-hooks")]
+
+use std::io::prelude::*;
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+mod common;
+use common::{interfaces, SERVER};
+
+#[test]
+fn blocked_readers_each_wake_up_for_their_own_stream() {
+    let (mut client, mut server) = interfaces();
+    let listener = server.bind(80).unwrap();
+    let mut streams = Vec::new();
+    let mut accepted = Vec::new();
+    for _ in 0..8 {
+        streams.push(client.connect(SocketAddrV4::new(SERVER, 80)).unwrap());
+        accepted.push(listener.accept().unwrap());
+    }
+    let ready = Arc::new(Barrier::new(streams.len() + 1));
+
+    let readers: Vec<_> = accepted.into_iter().enumerate().map(|(i, mut accepted)| {
+        let ready = ready.clone();
+        thread::spawn(move || {
+            ready.wait();
+            let mut buf = [0u8; 16];
+            assert_eq!(accepted.read(&mut buf).unwrap(), 1);
+            assert_eq!(buf[0], i as u8);
+            accepted.wakeups()
+        })
+    }).collect();
+    ready.wait();
+
+    // wake them up from last to first, whichever part of the connection table they are in, and
+    // let each reader finish before the next write
+    for (i, (stream, reader)) in streams.iter_mut().zip(readers).enumerate().rev() {
+        stream.write_all(&[i as u8]).unwrap();
+        // once for its data, and maybe once spuriously, but not for the data of the others
+        assert!(reader.join().unwrap() <= 2);
+    }
+}